@@ -1,8 +1,21 @@
-use reqwest::blocking::Client;
-use std::{fmt, io::Write};
+use log::{debug, trace, warn};
+use reqwest::{Method, blocking::{Client, RequestBuilder}};
+use std::{io::Read, collections::{HashMap, HashSet, VecDeque}, fmt, sync::{Arc, Mutex, PoisonError, atomic::{AtomicBool, Ordering}}, thread::JoinHandle, time::{Duration, Instant}};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-#[derive(Debug)]
+// WebSocket event stream, see Connection::connect_ws
+#[cfg(feature = "ws")]
+mod ws;
+#[cfg(feature = "ws")]
+pub use ws::EventStream;
+
+#[cfg(test)]
+mod mock;
+#[cfg(test)]
+mod tests;
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Error {
     pub id: i32,
     pub description: Option<String>,
@@ -19,10 +32,12 @@ impl std::error::Error for Error {}
 
 impl Error {
     pub fn from_json(json: &Value) -> Self {
-        Self {
-            id: json.get("id").unwrap().as_i64().unwrap() as i32,
-            description: Some(json.get("description").unwrap().as_str().unwrap().to_string()),
-            info: Some(json.get("info").unwrap().as_str().unwrap().to_string()),
+        let description = json.get("description").and_then(Value::as_str).map(String::from);
+        let info = json.get("info").and_then(Value::as_str).map(String::from);
+
+        match json.get("id").and_then(Value::as_i64) {
+            Some(id) => Self::new(id as i32, description, info),
+            None => Self::new(Self::LOCAL_ERROR_ID, Some("malformed error object".into()), Some(json.to_string())),
         }
     }
 
@@ -33,12 +48,117 @@ impl Error {
             info
         }
     }
+
+    /// Ids below zero are never sent by the server and are reserved for errors produced locally
+    pub const LOCAL_ERROR_ID: i32 = -1;
+    /// Sent by the server for a private game join code that is unknown or has expired
+    pub const INVALID_JOIN_CODE_ID: i32 = 11;
+    /// Sent by the server when a player asks for a nickname another player already has
+    pub const NICKNAME_TAKEN_ID: i32 = 12;
+
+    pub fn malformed_envelope() -> Self {
+        Self::new(Self::LOCAL_ERROR_ID, Some("malformed response envelope".into()), None)
+    }
+
+    /// Servers sometimes pack structured context into info, e.g. the id of a conflicting player
+    pub fn info_as_json(&self) -> Option<Value> {
+        serde_json::from_str(self.info.as_deref()?).ok()
+    }
 }
 
+#[derive(Debug)]
+pub enum ConnectionError {
+    Http(reqwest::Error),
+    Json(serde_json::Error),
+    Server(Error),
+    Protocol(String),
+    /// Non-2xx answer without a JSON envelope, with the start of its body
+    HttpStatus(reqwest::StatusCode, String),
+}
 
-#[derive(Clone)]
+impl ConnectionError {
+    pub fn server_error_id(&self) -> Option<i32> {
+        match self {
+            ConnectionError::Server(e) => Some(e.id),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionError::Http(e) => write!(f, "HTTP error: {e}"),
+            ConnectionError::Json(e) => write!(f, "JSON error: {e}"),
+            ConnectionError::Server(e) => write!(f, "Server error: {e}"),
+            ConnectionError::Protocol(e) => write!(f, "Protocol error: {e}"),
+            ConnectionError::HttpStatus(status, body) => write!(f, "HTTP status {status}: {body}"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConnectionError::Http(e) => Some(e),
+            ConnectionError::Json(e) => Some(e),
+            ConnectionError::Server(e) => Some(e),
+            ConnectionError::Protocol(_) | ConnectionError::HttpStatus(..) => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for ConnectionError {
+    fn from(e: reqwest::Error) -> Self {
+        ConnectionError::Http(e)
+    }
+}
+
+impl From<serde_json::Error> for ConnectionError {
+    fn from(e: serde_json::Error) -> Self {
+        ConnectionError::Json(e)
+    }
+}
+
+/// Errors with LOCAL_ERROR_ID were never sent by the server, they describe an answer that couldn't be read
+impl From<Error> for ConnectionError {
+    fn from(e: Error) -> Self {
+        if e.id != Error::LOCAL_ERROR_ID {
+            return ConnectionError::Server(e);
+        }
+        let description = e.description.unwrap_or_default();
+        match e.info {
+            Some(info) => ConnectionError::Protocol(format!("{description}: {info}")),
+            None => ConnectionError::Protocol(description),
+        }
+    }
+}
+
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DisconnectReason {
+    NetworkError,
+    ServerClosed,
+    Kicked,
+    UnknownStateId(u64),
+    Other(String),
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisconnectReason::NetworkError => write!(f, "server unreachable"),
+            DisconnectReason::ServerClosed => write!(f, "server closed the session"),
+            DisconnectReason::Kicked => write!(f, "kicked by the server"),
+            DisconnectReason::UnknownStateId(id) => write!(f, "unknown state id {id}"),
+            DisconnectReason::Other(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum State {
-    Disconnected(String),
+    Disconnected(DisconnectReason),
     Registration,
     Idle,
     Searching,
@@ -51,16 +171,40 @@ impl State {
             1 => State::Idle,
             2 => State::Searching,
             3 => State::Playing,
-            _ => State::Disconnected("Unknown state id".into()),
+            id => State::Disconnected(DisconnectReason::UnknownStateId(id)),
+        }
+    }
+
+    /// Disconnected is client-side only and has no id on the server
+    pub fn id(&self) -> Option<u64> {
+        match self {
+            State::Registration => Some(0),
+            State::Idle => Some(1),
+            State::Searching => Some(2),
+            State::Playing => Some(3),
+            State::Disconnected(_) => None,
         }
     }
+
+    fn from_json(json: &Value) -> Result<State, ConnectionError> {
+        let state_id = json.get("state").and_then(Value::as_u64)
+                           .ok_or_else(|| ConnectionError::Protocol("missing state id".into()))?;
+        Ok(State::from_id(state_id))
+    }
 }
 
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Player {
     pub nickname: String,
     pub id: u64,
 }
 
+impl fmt::Display for Player {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.nickname, self.id)
+    }
+}
+
 impl Player {
     pub fn new(nickname: &str, id: u64) -> Self {
         Self {
@@ -68,9 +212,29 @@ impl Player {
             id,
         }
     }
+
+    /// Parses the "id:nickname" form the server uses in player lists
+    pub fn from_entry(entry: &str) -> Result<Player, Error> {
+        let malformed = || Error::new(Error::LOCAL_ERROR_ID, Some("malformed player entry".into()), Some(entry.to_string()));
+
+        let colon_pos = entry.find(':').ok_or_else(malformed)?;
+        let id = entry[..colon_pos].parse().map_err(|_| malformed())?;
+        let nickname = &entry[colon_pos + 1..];
+
+        Ok(Player::new(nickname, id))
+    }
+
+    // Malformed entries are skipped so that a single bad row doesn't lose the whole list
+    fn list_from_json(json: &Value, key: &str) -> Result<Vec<Player>, Error> {
+        let y = json.get(key).and_then(Value::as_array).ok_or_else(Error::malformed_envelope)?;
+
+        Ok(y.iter()
+            .filter_map(Value::as_str)
+            .filter_map(|s| Player::from_entry(s).ok())
+            .collect())
+    }
 }
 
-#[allow(dead_code)]
 struct RegPlayerInfo {
     nickname: String,
     id: u64,
@@ -86,207 +250,1977 @@ impl RegPlayerInfo {
     }
 }
 
-pub struct Connection {
-    state: State,
-    info: Option<RegPlayerInfo>,
-    base_url: String,
-    client: Client,
+/// What the server answers register with, `player.id` is the id used in every later request
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Registration {
+    pub player: Player,
+    pub player_id: u64,
 }
 
-#[allow(dead_code)]
-impl Connection {
-    fn parse(resp: &Value) -> Result<&Value, Error> {
-        let resp = resp.as_object().unwrap();
-        if resp.contains_key("success") {
-            Ok(resp.get("success").unwrap())
+impl Registration {
+    fn from_json(resp: &Value) -> Result<Self, ConnectionError> {
+        let missing = |field: &str| ConnectionError::Protocol(format!("registration without {field}"));
+        let pl = resp.get("player").ok_or_else(|| missing("player"))?;
+
+        let nickname = pl.get("nickname").and_then(Value::as_str).ok_or_else(|| missing("nickname"))?;
+        let id = pl.get("id").and_then(Value::as_u64).ok_or_else(|| missing("id"))?;
+        let player_id = pl.get("player_id").and_then(Value::as_u64).ok_or_else(|| missing("player_id"))?;
+
+        Ok(Self { player: Player::new(nickname, id), player_id })
+    }
+}
+
+impl From<&Registration> for RegPlayerInfo {
+    fn from(registration: &Registration) -> Self {
+        Self::new(&registration.player.nickname, registration.player.id, registration.player_id)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RequestOutcome {
+    Queued,
+    GameStarted,
+}
+
+impl RequestOutcome {
+    fn from_json(json: &Value) -> Result<RequestOutcome, ConnectionError> {
+        match json.get("in_game").and_then(Value::as_bool) {
+            Some(true) => Ok(RequestOutcome::GameStarted),
+            Some(false) => Ok(RequestOutcome::Queued),
+            None => Err(ConnectionError::Protocol("missing in_game flag".into())),
         }
-        else {
-            Err(Error::from_json(resp.get("error").unwrap()))
+    }
+}
+
+// Uses the "count" field when the server sends one, otherwise the length of the `key` array
+fn count_from_json(json: &Value, key: &str) -> Result<usize, ConnectionError> {
+    if let Some(count) = json.get("count").and_then(Value::as_u64) {
+        return Ok(count as usize);
+    }
+    json.get(key).and_then(Value::as_array).map(Vec::len)
+        .ok_or_else(|| ConnectionError::Protocol(format!("missing {key} list")))
+}
+
+// The opponent is sent in the same "id:nickname" form as player lists
+fn opponent_from_json(json: &Value) -> Option<Player> {
+    json.get("opponent").and_then(Value::as_str).and_then(|s| Player::from_entry(s).ok())
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub sender_id: u64,
+    pub sender_nickname: String,
+    pub text: String,
+    pub timestamp: Option<u64>,
+}
+
+impl ChatMessage {
+    // Bare strings from older servers become messages with sender id 0 and an empty nickname
+    fn from_json(json: &Value) -> Option<Self> {
+        if let Some(text) = json.as_str() {
+            return Some(Self {
+                sender_id: 0,
+                sender_nickname: String::new(),
+                text: text.into(),
+                timestamp: None,
+            });
         }
+
+        Some(Self {
+            sender_id: json.get("sender_id").and_then(Value::as_u64).unwrap_or(0),
+            sender_nickname: json.get("sender_nickname").and_then(Value::as_str).unwrap_or_default().into(),
+            text: json.get("text").and_then(Value::as_str)?.into(),
+            timestamp: json.get("timestamp").and_then(Value::as_u64),
+        })
     }
+}
 
-    fn get(&self, command: &str, query: &[(&str, &str)], body: &str) -> Result<Value, Box<dyn std::error::Error>> {
-        //self.log(format!("Get request to /{command}\n\tQuery: {query:#?}\n\tBody: {body:#?}").as_str());
-        
-        let resp = self.client.get(format!("{}/{command}", self.base_url.as_str())).query(query).body(body.to_string()).send()?.json()?;
-        Ok(resp)
+fn chat_from_json(json: &Value) -> Result<Vec<ChatMessage>, ConnectionError> {
+    let messages = json.get("messages").and_then(Value::as_array)
+                       .ok_or_else(|| ConnectionError::Protocol("missing messages list".into()))?;
+    Ok(messages.iter().filter_map(ChatMessage::from_json).collect())
+}
+
+fn moves_from_json(json: &Value) -> Result<Vec<String>, ConnectionError> {
+    let moves = json.get("moves").and_then(Value::as_array)
+                    .ok_or_else(|| ConnectionError::Protocol("missing moves list".into()))?;
+    Ok(moves.iter().filter_map(Value::as_str).map(String::from).collect())
+}
+
+// The {"text": ...} form send_message and send_messages post, see send_raw_message for the bare one
+fn message_body(text: &str) -> Value {
+    serde_json::json!({ "text": text })
+}
+
+fn messages_from_json(json: &Value) -> Result<Vec<String>, ConnectionError> {
+    Ok(chat_from_json(json)?.into_iter().map(|m| m.text).collect())
+}
+
+/// Nicknames end up in "id:nickname" entries, so they can't be empty or contain the separator
+pub fn validate_nickname(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        Err("nickname must not be empty".into())
     }
-    fn get_parsed(&self, command: &str, query: &[(&str, &str)], body: &str) -> Result<Value, Box<dyn std::error::Error>> {
-        let x = self.get(command, query, body)?;
-        Ok(Connection::parse(&x)?.clone())
+    else if name.contains(':') {
+        Err("nickname must not contain ':'".into())
     }
+    else {
+        Ok(())
+    }
+}
+
+// Servers report their errors in a JSON envelope whatever the status, so a non-2xx answer is only read
+// when it carries an "error". Anything else (an HTML page or a gateway's own JSON, say) is HttpStatus.
+fn status_error(status: reqwest::StatusCode, body: &str) -> Result<Value, ConnectionError> {
+    match serde_json::from_str::<Value>(body) {
+        Ok(json) if json.get("error").is_some() => Ok(json),
+        _ => Err(ConnectionError::HttpStatus(status, body.chars().take(200).collect())),
+    }
+}
 
-    fn get_with_id(&self, command: &str, query: &[(&str, &str)], body: &str) -> Result<Value, Box<dyn std::error::Error>> {
-        self.get(format!("/{}/{command}", self.info.as_ref().unwrap().id).as_str(), query, body)
+// JSON arrays and objects are labelled as such, every other body as plain text
+fn default_content_type(body: &str) -> &'static str {
+    if body.starts_with(['[', '{']) && serde_json::from_str::<Value>(body).is_ok() {
+        "application/json"
     }
-    fn get_parsed_with_id(&self, command: &str, query: &[(&str, &str)], body: &str) -> Result<Value, Box<dyn std::error::Error>> {
-        self.get_parsed(format!("/{}/{command}", self.info.as_ref().unwrap().id).as_str(), query, body)
+    else {
+        "text/plain; charset=utf-8"
     }
+}
 
+// Query values are formatted when the request is built, so numbers can be passed as they are
+type Query<'a> = [(&'a str, &'a dyn fmt::Display)];
 
-    fn post(&self, command: &str, query: &[(&str, &str)], body: &str) -> Result<Value, Box<dyn std::error::Error>> {
-        //self.log(format!("Post request to /{command}\n\tQuery: {query:#?}\n\tBody: {body:#?}").as_str());
-        
-        let resp = self.client.post(format!("{}/{command}", self.base_url.as_str())).query(query).body(body.to_string()).send()?.json()?;
-        Ok(resp)
+fn query_pairs<'a>(query: &'a Query) -> Vec<(&'a str, String)> {
+    query.iter().map(|(k, v)| (*k, v.to_string())).collect()
+}
+
+fn raw_query<'a>(query: &'a [(&'a str, &'a str)]) -> Vec<(&'a str, &'a dyn fmt::Display)> {
+    query.iter().map(|(k, v)| (*k, v as &dyn fmt::Display)).collect()
+}
+
+// The base url may carry a path prefix ("http://host/api"), commands are appended after it
+fn normalize_base_url(url: &str) -> String {
+    url.trim_end_matches('/').into()
+}
+
+pub struct ConnectionBuilder {
+    base_url: String,
+    timeout: Option<Duration>,
+    user_agent: Option<String>,
+    proxy: Option<String>,
+}
+
+impl ConnectionBuilder {
+    pub fn new(url: &str) -> Self {
+        Self {
+            base_url: normalize_base_url(url),
+            timeout: None,
+            user_agent: None,
+            proxy: None,
+        }
     }
-    fn post_parsed(&self, command: &str, query: &[(&str, &str)], body: &str) -> Result<Value, Box<dyn std::error::Error>> {
-        let x = self.post(command, query, body)?;
-        Ok(Connection::parse(&x)?.clone())
+
+    pub fn base_url(mut self, url: &str) -> Self {
+        self.base_url = normalize_base_url(url);
+        self
+    }
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
     }
+    pub fn user_agent(mut self, user_agent: &str) -> Self {
+        self.user_agent = Some(user_agent.into());
+        self
+    }
+    pub fn proxy(mut self, proxy_url: &str) -> Self {
+        self.proxy = Some(proxy_url.into());
+        self
+    }
+
+    pub fn build(self) -> Result<Connection, ConnectionError> {
+        let mut client = Client::builder();
+        if let Some(timeout) = self.timeout {
+            client = client.timeout(timeout);
+        }
+        if let Some(user_agent) = self.user_agent {
+            client = client.user_agent(user_agent);
+        }
+        if let Some(proxy) = self.proxy {
+            client = client.proxy(reqwest::Proxy::all(proxy)?);
+        }
 
-    fn post_with_id(&self, command: &str, query: &[(&str, &str)], body: &str) -> Result<Value, Box<dyn std::error::Error>> {
-        self.post(format!("/{}/{command}", self.info.as_ref().unwrap().id).as_str(), query, body)
+        Ok(Connection::with_client(&self.base_url, client.build()?))
     }
-    fn post_parsed_with_id(&self, command: &str, query: &[(&str, &str)], body: &str) -> Result<Value, Box<dyn std::error::Error>> {
-        self.post_parsed(format!("/{}/{command}", self.info.as_ref().unwrap().id).as_str(), query, body)
+}
+
+/// A draw has no winner
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GameResult {
+    pub winner_id: Option<u64>,
+    pub reason: String,
+}
+
+impl GameResult {
+    fn from_json(json: &Value) -> Self {
+        Self {
+            winner_id: json.get("winner_id").and_then(Value::as_u64),
+            reason: json.get("reason").and_then(Value::as_str).unwrap_or_default().into(),
+        }
     }
 }
 
-impl Connection {
-    pub fn new(url: &str) -> Self {
+/// Limits the server advertises, each one may be left out
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ServerConfig {
+    pub max_message_length: Option<usize>,
+    pub max_nickname_length: Option<usize>,
+    pub request_cooldown: Option<Duration>,
+}
+
+impl ServerConfig {
+    fn from_json(json: &Value) -> Self {
+        let limit = |key: &str| json.get(key).and_then(Value::as_u64);
         Self {
-            state: State::Registration,
-            info: None,
-            base_url: url.into(),
-            client: Client::new(),
+            max_message_length: limit("max_message_length").map(|n| n as usize),
+            max_nickname_length: limit("max_nickname_length").map(|n| n as usize),
+            request_cooldown: limit("request_cooldown_ms").map(Duration::from_millis),
         }
     }
+}
 
-    pub fn test_connection(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let _ = self.post("test", &[], "")?;
-        Ok(())
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GameSummary {
+    pub game_id: u64,
+    pub players: (Player, Player),
+}
+
+impl GameSummary {
+    // Entries look like {"game_id": 7, "players": ["1:alice", "2:bob"]}
+    fn from_json(json: &Value) -> Option<Self> {
+        let game_id = json.get("game_id").and_then(Value::as_u64)?;
+        let players = json.get("players").and_then(Value::as_array)?;
+        let [first, second] = &players[..] else { return None };
+        let player = |entry: &Value| entry.as_str().and_then(|s| Player::from_entry(s).ok());
+
+        Some(Self { game_id, players: (player(first)?, player(second)?) })
     }
+}
+
+/// Missing counts are 0, players without a rating yet have None
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PlayerStats {
+    pub wins: u32,
+    pub losses: u32,
+    pub draws: u32,
+    pub rating: Option<f64>,
+}
 
-    pub fn get_error_description(&self, error_id: i32) -> Result<String, Box<dyn std::error::Error>> {
-        Ok(self.get_parsed("error_description", &[("id", error_id.to_string().as_str())], "")?
-               .as_object().unwrap()
-               .get("description").unwrap()
-               .as_str().unwrap().to_string())
+impl PlayerStats {
+    fn from_json(json: &Value) -> Self {
+        let count = |key: &str| json.get(key).and_then(Value::as_u64).unwrap_or(0) as u32;
+        Self {
+            wins: count("wins"),
+            losses: count("losses"),
+            draws: count("draws"),
+            rating: json.get("rating").and_then(Value::as_f64),
+        }
     }
+}
 
-    pub fn get_players(&self) -> Result<Vec<Player>, Box<dyn std::error::Error>> {
-        let mut res = vec![];
+/// Matchmaking filters, unset fields don't restrict the pool
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SearchPrefs {
+    pub min_rating: Option<u32>,
+    pub max_rating: Option<u32>,
+    pub game_mode: Option<String>,
+}
 
-        let x = self.get_parsed("players", &[], "")?;
-        let y = x.as_object().unwrap().get("players").unwrap().as_array().unwrap();
-        for z in y {
-            let s = z.as_str().unwrap();
-            let colon_pos = s.find(':').unwrap();
-            let id = s[..colon_pos].parse().unwrap();
-            let nickname = &s[colon_pos + 1..];
+impl SearchPrefs {
+    fn query(&self) -> Vec<(&str, &dyn fmt::Display)> {
+        let mut query: Vec<(&str, &dyn fmt::Display)> = vec![];
+        if let Some(min_rating) = &self.min_rating {
+            query.push(("min_rating", min_rating));
+        }
+        if let Some(max_rating) = &self.max_rating {
+            query.push(("max_rating", max_rating));
+        }
+        if let Some(game_mode) = &self.game_mode {
+            query.push(("game_mode", game_mode));
+        }
+        query
+    }
+}
+
+#[derive(Debug)]
+pub enum Event {
+    StateChanged(State),
+    NewRequest(Player),
+    RequestWithdrawn(u64),
+    NewMessage(String),
+    GameStarted,
+    GameEnded,
+    /// A poll that failed inside run_loop
+    Error(ConnectionError),
+}
+
+/// What a run_loop handler wants to happen after an event
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoopControl {
+    Continue,
+    Stop,
+}
+
+// What poll_events saw last time, so that only the differences are reported
+#[derive(Default)]
+struct EventSnapshot {
+    state: Option<State>,
+    requests: Vec<Player>,
+    message_cursor: usize,
+}
+
+// Token bucket holding up to one second worth of requests
+struct RateLimiter {
+    per_sec: f64,
+    tokens: f64,
+    last: Instant,
+}
 
-            res.push(Player::new(nickname, id));
+impl RateLimiter {
+    fn new(max_per_sec: u32) -> Self {
+        Self {
+            per_sec: max_per_sec as f64,
+            tokens: max_per_sec as f64,
+            last: Instant::now(),
         }
-        
-        Ok(res)
     }
 
-    pub fn get_nickname(&self) -> Result<String, Box<dyn std::error::Error>> {
-        Ok(self.info.as_ref().unwrap().nickname.clone())
+    fn wait(&mut self) {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * self.per_sec).min(self.per_sec);
+        self.last = now;
+
+        if self.tokens < 1.0 {
+            std::thread::sleep(Duration::from_secs_f64((1.0 - self.tokens) / self.per_sec));
+            self.tokens = 1.0;
+            self.last = Instant::now();
+        }
+        self.tokens -= 1.0;
     }
+}
 
-    pub fn ping(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let _ = self.post_with_id("ping", &[], "")?;
-        Ok(())
+/// Hands out connections to one server that all share a single client and its connection pool.
+/// The client is reference counted and Send + Sync, so the pool can be cloned into any number of threads.
+#[derive(Clone)]
+pub struct ConnectionPool {
+    base_url: String,
+    client: Client,
+}
+
+impl ConnectionPool {
+    pub fn new(url: &str) -> Self {
+        Self::with_client(url, Client::new())
+    }
+    pub fn with_client(url: &str, client: Client) -> Self {
+        Self {
+            base_url: normalize_base_url(url),
+            client,
+        }
+    }
+
+    pub fn connection(&self) -> Connection {
+        Connection::with_client(&self.base_url, self.client.clone())
     }
+}
+
+// Nicknames seen in the last get_players call
+#[derive(Default)]
+struct PlayerCache {
+    nicknames: HashMap<u64, String>,
+    updated: Option<Instant>,
+}
 
-    pub fn get_state(&mut self) -> Result<State, Box<dyn std::error::Error>> {
-        let state_id = self.get_parsed_with_id("state", &[], "")?
-                                  .as_object().unwrap()
-                                  .get("state").unwrap()
-                                  .as_u64().unwrap();
-        let state = State::from_id(state_id);
-        if let State::Disconnected(_) = state {
+struct RetryPolicy {
+    max_retries: u32,
+    base_delay: Duration,
+}
+
+pub type RequestObserver = Box<dyn Fn(&str, Duration) + Send + Sync>;
+pub type StateObserver = Box<dyn FnMut(&State, &State) + Send + Sync>;
+
+pub struct Connection {
+    state: State,
+    info: Option<RegPlayerInfo>,
+    opponent: Option<Player>,
+    current_game: Option<u64>,
+    spectating: Option<u64>,
+    base_url: String,
+    client: Client,
+    retry: Option<RetryPolicy>,
+    rate_limit: Option<Mutex<RateLimiter>>,
+    auth_token: Option<String>,
+    content_type: Option<String>,
+    compression: bool,
+    observer: Option<RequestObserver>,
+    state_observer: Option<StateObserver>,
+    dry_run: bool,
+    player_cache: Mutex<PlayerCache>,
+    player_cache_ttl: Option<Duration>,
+    last_response: Mutex<Option<Value>>,
+    move_cursor: usize,
+    match_poll_interval: Duration,
+    retry_error_ids: Vec<i32>,
+    max_response_bytes: usize,
+    server_config: Mutex<Option<ServerConfig>>,
+    last_request_sent: Option<Instant>,
+    auto_unregister: bool,
+    version_checked: AtomicBool,
+    snapshot: EventSnapshot,
+    #[cfg(feature = "idempotency")]
+    last_idempotency_key: Option<String>,
+}
 
+#[allow(dead_code)]
+impl Connection {
+    fn parse(resp: &Value) -> Result<&Value, Error> {
+        let resp = resp.as_object().ok_or_else(Error::malformed_envelope)?;
+        if let Some(success) = resp.get("success") {
+            Ok(success)
+        }
+        else if let Some(error) = resp.get("error") {
+            let error = Error::from_json(error);
+            warn!("Server returned an error: {error}");
+            Err(error)
+        }
+        else {
+            Err(Error::malformed_envelope())
         }
-        self.state = state.clone();
-        Ok(state)
     }
-    pub fn get_stored_state(&self) -> State {
-        self.state.clone()
+
+    fn set_state(&mut self, state: State) {
+        if self.state != state {
+            debug!("State {:?} -> {:?}", self.state, state);
+            let old = std::mem::replace(&mut self.state, state);
+            if let Some(observer) = &mut self.state_observer {
+                observer(&old, &self.state);
+            }
+        }
     }
 
-    pub fn disconnect(&self) {
-        
+    // Success envelopes may carry the server's view of our state, which always wins over local bookkeeping
+    fn sync_state(&mut self, resp: &Value) -> bool {
+        match resp.get("state").and_then(Value::as_u64) {
+            Some(state_id) => {
+                self.set_state(State::from_id(state_id));
+                true
+            }
+            None => false,
+        }
     }
 
-    pub fn register(&mut self, nickname: String) -> Result<(), Box<dyn std::error::Error>> {
-        let resp = self.post_parsed("register", &[("name", nickname.as_str())], "")?;
-        let pl =  resp.as_object().unwrap()
-                                        .get("player").unwrap()
-                                        .as_object().unwrap();
+    // Moves to `state` unless the server already said otherwise
+    fn transition(&mut self, resp: &Value, state: State) {
+        if !self.sync_state(resp) {
+            self.set_state(state);
+        }
+    }
 
-        let nickname = pl.get("nickname").unwrap().as_str().unwrap();
-        let id = pl.get("id").unwrap().as_u64().unwrap();
-        let player_id = pl.get("player_id").unwrap().as_u64().unwrap();
-        
-        self.info = Some(RegPlayerInfo::new(nickname, id, player_id));
-        self.state = State::Idle;
+    fn url(&self, command: &str) -> String {
+        format!("{}/{}", self.base_url, command.trim_start_matches('/'))
+    }
 
-        Ok(())
+    fn request(&self, method: Method, command: &str) -> RequestBuilder {
+        let mut request = self.client.request(method, self.url(command));
+        // An explicit Accept-Encoding stops reqwest from asking for gzip
+        if !self.compression {
+            request = request.header(reqwest::header::ACCEPT_ENCODING, "identity");
+        }
+        match &self.auth_token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
     }
 
-    pub fn search(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let _ = self.post_parsed_with_id("search", &[], "")?;
-        self.state = State::Searching;
+    // Empty bodies go without a Content-Type, otherwise the with_content_type one or the default one is sent
+    fn body(&self, request: RequestBuilder, body: &str) -> RequestBuilder {
+        if body.is_empty() {
+            return request;
+        }
+        let content_type = match &self.content_type {
+            Some(content_type) => content_type.as_str(),
+            None => default_content_type(body),
+        };
+        request.header(reqwest::header::CONTENT_TYPE, content_type).body(body.to_string())
+    }
 
-        Ok(())
+    fn require_registered(&self) -> Result<&RegPlayerInfo, ConnectionError> {
+        self.info.as_ref().ok_or_else(|| ConnectionError::Protocol("not registered".into()))
     }
-    pub fn idle(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let _ = self.post_parsed_with_id("idle", &[], "")?;
-        self.state = State::Idle;
 
-        Ok(())
+    fn start_game(&mut self, resp: &Value) {
+        self.transition(resp, State::Playing);
+        self.opponent = opponent_from_json(resp);
+        self.current_game = resp.get("game_id").and_then(Value::as_u64);
+        self.move_cursor = 0;
     }
 
-    pub fn send_request(&mut self, send_to: u64) -> Result<(), Box<dyn std::error::Error>> {
-        let x = self.post_parsed_with_id("requests", &[("send_to", send_to.to_string().as_str())], "")?;
-        let in_game = x.as_object().unwrap().get("in_game").unwrap().as_bool().unwrap();
+    fn finish_game(&mut self) {
+        debug!("Game {:?} against {:?} ended", self.current_game, self.opponent);
+        self.opponent = None;
+        self.current_game = None;
+        self.move_cursor = 0;
+    }
 
-        if in_game {
-            self.state = State::Playing;
+    // Without an observer the clock isn't even read
+    fn send(&self, command: &str, request: RequestBuilder, long_poll: Option<Duration>) -> Result<Value, ConnectionError> {
+        if self.dry_run {
+            return self.dry_run_response(command, request);
         }
-        Ok(())
+        let Some(observer) = &self.observer else {
+            return self.send_attempts(request, long_poll);
+        };
+        let started = Instant::now();
+        let resp = self.send_attempts(request, long_poll);
+        observer(command, started.elapsed());
+        resp
     }
-    pub fn get_requests(&mut self) -> Result<Vec<Player>, Box<dyn std::error::Error>> {
-        let mut res = vec![];
 
-        let x = self.get_parsed_with_id("requests", &[], "")?;
-        let y = x.as_object().unwrap().get("requests").unwrap().as_array().unwrap();
-        for z in y {
-            let s = z.as_str().unwrap();
-            let colon_pos = s.find(':').unwrap();
-            let id = s[..colon_pos].parse().unwrap();
-            let nickname = &s[colon_pos + 1..];
+    // One success envelope that every wrapped method can read something benign from
+    fn dry_run_response(&self, command: &str, request: RequestBuilder) -> Result<Value, ConnectionError> {
+        let request = request.build()?;
+        let body = request.body().and_then(|b| b.as_bytes()).map(String::from_utf8_lossy).unwrap_or_default();
+        debug!("Dry run, not sending {} {} body: {body:?}", request.method(), request.url());
 
-            res.push(Player::new(nickname, id));
+        let mut success = serde_json::json!({
+            "player": { "nickname": "dry-run", "id": 0, "player_id": 0 },
+            "players": [],
+            "requests": [],
+            "messages": [],
+            "leaderboard": [],
+            "moves": [],
+            "games": [],
+            "in_game": false,
+            "code": "dry-run",
+            "description": "",
+            "version": Self::SUPPORTED_VERSION,
+            "time": 0,
+        });
+        // Only state queries report one, so that local transitions still happen everywhere else
+        if command.ends_with("state") {
+            success["state"] = serde_json::json!(self.state.id());
         }
-        
-        Ok(res)
+        Ok(serde_json::json!({ "success": success }))
     }
 
-    pub fn send_message(&mut self, message: String) -> Result<(), Box<dyn std::error::Error>> {
-        let _ = self.post_parsed_with_id("messages", &[], message.as_str())?;
+    // A long poll runs with its own timeout. Running out of it is the expected empty answer and is never retried,
+    // other failures are retried with whatever is left of the timeout, as clones don't keep it.
+    fn send_attempts(&self, mut request: RequestBuilder, long_poll: Option<Duration>) -> Result<Value, ConnectionError> {
+        let deadline = long_poll.map(|timeout| Instant::now() + timeout);
+        if let Some(timeout) = long_poll {
+            request = request.timeout(timeout);
+        }
+
+        let mut attempt = 0;
+        loop {
+            // try_clone only fails for streamed bodies, which are never used here
+            let retry = match &self.retry {
+                Some(policy) if attempt < policy.max_retries => request.try_clone().map(|next| (policy.base_delay, next)),
+                _ => None,
+            };
 
-        Ok(())
+            if let Some(limiter) = &self.rate_limit {
+                limiter.lock().unwrap_or_else(PoisonError::into_inner).wait();
+            }
+            match (request.send(), retry) {
+                (Err(e), Some((base_delay, next))) if Self::is_transient(&e) && !(long_poll.is_some() && e.is_timeout()) => {
+                    std::thread::sleep(base_delay * 2u32.saturating_pow(attempt));
+                    attempt += 1;
+                    request = match deadline {
+                        Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                            Some(remaining) if !remaining.is_zero() => next.timeout(remaining),
+                            _ => return Err(e.into()),
+                        },
+                        None => next,
+                    };
+                }
+                (resp, _) => {
+                    let resp = resp?;
+                    let status = resp.status();
+                    let body = self.read_body(resp)?;
+                    let resp: Value = if status.is_success() {
+                        serde_json::from_slice(&body)?
+                    }
+                    else {
+                        status_error(status, &String::from_utf8_lossy(&body))?
+                    };
+                    trace!("Response: {resp}");
+                    *self.last_response.lock().unwrap_or_else(PoisonError::into_inner) = Some(resp.clone());
+                    return Ok(resp);
+                }
+            }
+        }
     }
-    pub fn get_messages(&mut self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-        let mut res = vec![];
 
-        let x = self.get_parsed_with_id("messages", &[], "")?;
-        let y = x.as_object().unwrap().get("messages").unwrap().as_array().unwrap();
+    // Reads at most max_response_bytes instead of buffering whatever the server sends
+    fn read_body(&self, resp: reqwest::blocking::Response) -> Result<Vec<u8>, ConnectionError> {
+        let too_large = || ConnectionError::Protocol("response too large".into());
+        if resp.content_length().is_some_and(|len| len > self.max_response_bytes as u64) {
+            return Err(too_large());
+        }
+
+        let mut body = vec![];
+        resp.take(self.max_response_bytes as u64 + 1).read_to_end(&mut body)
+            .map_err(Self::read_error)?;
+        if body.len() > self.max_response_bytes {
+            return Err(too_large());
+        }
+        Ok(body)
+    }
 
-        for z in y {
-            let s = z.as_str().unwrap();
-            res.push(s.to_string());
+    // Reading the body wraps reqwest's errors in io::Error, unwrapping them keeps is_timeout and friends working
+    fn read_error(e: std::io::Error) -> ConnectionError {
+        let message = e.to_string();
+        match e.into_inner().map(|inner| inner.downcast::<reqwest::Error>()) {
+            Some(Ok(e)) => ConnectionError::Http(*e),
+            _ => ConnectionError::Protocol(format!("failed to read response: {message}")),
         }
+    }
 
-        Ok(res)
+    fn is_transient(e: &reqwest::Error) -> bool {
+        e.is_connect() || e.is_timeout() || e.is_request()
     }
 
-    pub fn end_game(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let _ = self.post_parsed_with_id("end_game", &[], "")?;
-        Ok(())
+    fn get(&self, command: &str, query: &Query<'_>, body: &str) -> Result<Value, ConnectionError> {
+        let query = query_pairs(query);
+        debug!("GET /{command} query: {query:?}");
+        self.send(command, self.body(self.request(Method::GET, command).query(&query), body), None)
+    }
+    fn get_parsed(&self, command: &str, query: &Query<'_>, body: &str) -> Result<Value, ConnectionError> {
+        self.parse_retrying(|| self.get(command, query, body))
     }
-}
\ No newline at end of file
+
+    // Sends again while the server answers with one of retry_error_ids. Attempts and delays follow
+    // with_retries when it was called, otherwise up to 3 retries starting at 500ms.
+    fn parse_retrying(&self, send: impl Fn() -> Result<Value, ConnectionError>) -> Result<Value, ConnectionError> {
+        let (max_retries, base_delay) = match &self.retry {
+            Some(policy) => (policy.max_retries, policy.base_delay),
+            None => (3, Duration::from_millis(500)),
+        };
+
+        let mut attempt = 0;
+        loop {
+            let x = send()?;
+            match Connection::parse(&x) {
+                Ok(x) => return Ok(x.clone()),
+                Err(e) if attempt < max_retries && self.retry_error_ids.contains(&e.id) => {
+                    debug!("Retrying after server error {}", e.id);
+                    std::thread::sleep(base_delay * 2u32.saturating_pow(attempt));
+                    attempt += 1;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    // For endpoints older servers lack, which answer with something that isn't JSON
+    fn get_parsed_optional(&self, command: &str, query: &Query<'_>) -> Result<Value, ConnectionError> {
+        match self.get_parsed(command, query, "") {
+            Err(ConnectionError::HttpStatus(reqwest::StatusCode::NOT_FOUND, _)) => Err(ConnectionError::Protocol(format!("{command} is not supported by the server"))),
+            Err(ConnectionError::Json(_)) => Err(ConnectionError::Protocol(format!("{command} is not supported by the server"))),
+            x => x,
+        }
+    }
+
+    fn get_with_id(&self, command: &str, query: &Query<'_>, body: &str) -> Result<Value, ConnectionError> {
+        self.get(format!("{}/{command}", self.require_registered()?.id).as_str(), query, body)
+    }
+    fn get_parsed_with_id(&self, command: &str, query: &Query<'_>, body: &str) -> Result<Value, ConnectionError> {
+        self.get_parsed(format!("{}/{command}", self.require_registered()?.id).as_str(), query, body)
+    }
+    fn get_parsed_with_id_timeout(&self, command: &str, query: &Query<'_>, timeout: Duration) -> Result<Value, ConnectionError> {
+        let command = format!("{}/{command}", self.require_registered()?.id);
+        let query = query_pairs(query);
+        debug!("GET /{command} query: {query:?} timeout: {timeout:?}");
+        let x = self.send(&command, self.request(Method::GET, &command).query(&query), Some(timeout))?;
+        Ok(Connection::parse(&x)?.clone())
+    }
+
+
+    fn post(&self, command: &str, query: &Query<'_>, body: &str) -> Result<Value, ConnectionError> {
+        let query = query_pairs(query);
+        debug!("POST /{command} query: {query:?}");
+        self.send(command, self.body(self.request(Method::POST, command).query(&query), body), None)
+    }
+    fn post_parsed(&self, command: &str, query: &Query<'_>, body: &str) -> Result<Value, ConnectionError> {
+        self.parse_retrying(|| self.post(command, query, body))
+    }
+
+    fn post_with_id(&self, command: &str, query: &Query<'_>, body: &str) -> Result<Value, ConnectionError> {
+        self.post(format!("{}/{command}", self.require_registered()?.id).as_str(), query, body)
+    }
+    fn post_parsed_with_id(&self, command: &str, query: &Query<'_>, body: &str) -> Result<Value, ConnectionError> {
+        self.post_parsed(format!("{}/{command}", self.require_registered()?.id).as_str(), query, body)
+    }
+
+    fn get_synced_with_id(&mut self, command: &str, query: &Query<'_>, body: &str) -> Result<Value, ConnectionError> {
+        let x = self.get_parsed_with_id(command, query, body)?;
+        self.sync_state(&x);
+        Ok(x)
+    }
+    fn post_synced_with_id(&mut self, command: &str, query: &Query<'_>, body: &str) -> Result<Value, ConnectionError> {
+        let x = self.post_parsed_with_id(command, query, body)?;
+        self.sync_state(&x);
+        Ok(x)
+    }
+
+    // For POSTs that must not be applied twice. The key is part of the built request,
+    // so the retries in send reuse it and the server can drop the duplicates.
+    #[cfg(feature = "idempotency")]
+    fn post_idempotent_with_id(&mut self, command: &str, query: &Query<'_>, body: &str) -> Result<Value, ConnectionError> {
+        let key = uuid::Uuid::new_v4().to_string();
+        let mut keyed = query.to_vec();
+        keyed.push(("idempotency_key", &key));
+        self.last_idempotency_key = Some(key.clone());
+        self.post_synced_with_id(command, &keyed, body)
+    }
+    #[cfg(not(feature = "idempotency"))]
+    fn post_idempotent_with_id(&mut self, command: &str, query: &Query<'_>, body: &str) -> Result<Value, ConnectionError> {
+        self.post_synced_with_id(command, query, body)
+    }
+}
+
+impl Connection {
+    // Long polls ask the server to hold the request for a while (the "wait" query, in whole seconds) and give
+    // the request its own timeout of that hold time plus this margin, so that the server's empty answer wins
+    // the race against the client giving up. The per-request timeout takes precedence over the one set on the
+    // client, and the polls go through the same client so its kept-alive connections are reused.
+    const LONG_POLL_MARGIN: Duration = Duration::from_secs(5);
+    // Pause after an empty answer that came back before the deadline, so that a server which ignores "wait"
+    // isn't polled in a hot loop
+    const LONG_POLL_REPOLL_DELAY: Duration = Duration::from_secs(1);
+    const DEFAULT_MAX_RESPONSE_BYTES: usize = 16 * 1024 * 1024;
+    /// Major version of the server API this crate speaks
+    pub const SUPPORTED_VERSION: &'static str = "1";
+
+    pub fn new(url: &str) -> Self {
+        Self::with_client(url, Client::new())
+    }
+    /// The supplied client is used as is, so its timeout and redirect policy are up to the caller.
+    /// Clients are cheap to clone and share their connection pool between clones.
+    pub fn with_client(url: &str, client: Client) -> Self {
+        Self {
+            state: State::Registration,
+            info: None,
+            opponent: None,
+            current_game: None,
+            spectating: None,
+            base_url: normalize_base_url(url),
+            client,
+            retry: None,
+            rate_limit: None,
+            auth_token: None,
+            content_type: None,
+            compression: true,
+            observer: None,
+            state_observer: None,
+            dry_run: false,
+            player_cache: Mutex::default(),
+            player_cache_ttl: None,
+            last_response: Mutex::default(),
+            move_cursor: 0,
+            match_poll_interval: Duration::from_secs(1),
+            retry_error_ids: vec![],
+            max_response_bytes: Self::DEFAULT_MAX_RESPONSE_BYTES,
+            server_config: Mutex::default(),
+            last_request_sent: None,
+            auto_unregister: true,
+            version_checked: AtomicBool::new(false),
+            snapshot: EventSnapshot::default(),
+            #[cfg(feature = "idempotency")]
+            last_idempotency_key: None,
+        }
+    }
+    pub fn with_timeout(url: &str, timeout: Duration) -> Result<Self, ConnectionError> {
+        ConnectionBuilder::new(url).timeout(timeout).build()
+    }
+    pub fn with_root_cert(url: &str, pem: &[u8]) -> Result<Self, ConnectionError> {
+        let cert = reqwest::Certificate::from_pem(pem)?;
+        Ok(Self::with_client(url, Client::builder().add_root_certificate(cert).build()?))
+    }
+
+    /// Retries requests that failed to reach the server, waiting base_delay * 2^attempt in between.
+    /// Errors returned by the server itself are never retried.
+    pub fn with_retries(mut self, max_retries: u32, base_delay: Duration) -> Self {
+        self.retry = Some(RetryPolicy { max_retries, base_delay });
+        self
+    }
+    /// Throttles outgoing requests, including retries, to at most `max_per_sec`. Zero disables the limit.
+    pub fn with_rate_limit(mut self, max_per_sec: u32) -> Self {
+        self.rate_limit = (max_per_sec > 0).then(|| Mutex::new(RateLimiter::new(max_per_sec)));
+        self
+    }
+
+    /// Larger responses fail with a Protocol error instead of being read into memory, 16 MiB by default.
+    /// The limit applies to the decompressed body.
+    pub fn max_response_bytes(mut self, max: usize) -> Self {
+        self.max_response_bytes = max;
+        self
+    }
+
+    /// Responses are requested gzip-compressed and decompressed transparently, unless disabled
+    /// for servers that mishandle compression
+    pub fn with_compression(mut self, enabled: bool) -> Self {
+        self.compression = enabled;
+        self
+    }
+
+    /// Sent with every request that has a body instead of the guessed application/json or text/plain
+    pub fn with_content_type(mut self, content_type: &str) -> Self {
+        self.content_type = Some(content_type.to_string());
+        self
+    }
+
+    /// Called after every round trip with the command and the time it took, retries included.
+    /// Failed requests are reported too.
+    pub fn set_request_observer(&mut self, observer: RequestObserver) {
+        self.observer = Some(observer);
+    }
+
+    /// The last JSON the server answered with, envelope included, kept even when parsing it failed.
+    /// Answers that weren't JSON at all and dry-run responses don't replace it.
+    pub fn last_response(&self) -> Option<Value> {
+        self.last_response.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// How often wait_until_matched asks for the state, one second by default
+    pub fn with_match_poll_interval(mut self, interval: Duration) -> Self {
+        self.match_poll_interval = interval;
+        self
+    }
+
+    /// resolve_nickname stops answering once the cached players are older than `ttl`
+    pub fn with_player_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.player_cache_ttl = Some(ttl);
+        self
+    }
+
+    /// Called with the old and the new state on every change of the stored state, whichever method made it
+    pub fn on_state_change(&mut self, observer: StateObserver) {
+        self.state_observer = Some(observer);
+    }
+
+    /// Nothing is sent while enabled, the request is logged at debug level and answered locally:
+    /// lists (players, requests, messages, leaderboard, moves, active games) are empty, requests are queued
+    /// rather than starting a game, register gives player 0 named "dry-run", private games get the join code
+    /// "dry-run", error descriptions are empty, get_state returns the stored state, the server time is 0
+    /// and the version is SUPPORTED_VERSION. Everything else simply succeeds.
+    pub fn dry_run(&mut self, enabled: bool) {
+        self.dry_run = enabled;
+    }
+
+    pub fn disable_auto_unregister(&mut self) {
+        self.auto_unregister = false;
+    }
+
+    /// Key attached to the last send_request or send_message, to match it up with server logs
+    #[cfg(feature = "idempotency")]
+    pub fn last_idempotency_key(&self) -> Option<&str> {
+        self.last_idempotency_key.as_deref()
+    }
+
+    /// Server errors with these ids are treated as temporary and the request is sent again, see parse_retrying.
+    /// Only list errors the server raises before acting on the request, a retried POST is otherwise applied twice.
+    pub fn retry_on_error_ids(&mut self, ids: &[i32]) {
+        self.retry_error_ids = ids.to_vec();
+    }
+
+    /// Sent as `Authorization: Bearer <token>` with every request, None stops sending it
+    pub fn set_auth_token(&mut self, token: Option<&str>) {
+        self.auth_token = token.map(String::from);
+    }
+
+    /// Escape hatches for endpoints this crate doesn't wrap yet; the response envelope is still parsed
+    pub fn raw_get(&self, command: &str, query: &[(&str, &str)], body: &str) -> Result<Value, ConnectionError> {
+        self.get_parsed(command, &raw_query(query), body)
+    }
+    pub fn raw_post(&self, command: &str, query: &[(&str, &str)], body: &str) -> Result<Value, ConnectionError> {
+        self.post_parsed(command, &raw_query(query), body)
+    }
+    pub fn raw_get_with_id(&self, command: &str, query: &[(&str, &str)], body: &str) -> Result<Value, ConnectionError> {
+        self.get_parsed_with_id(command, &raw_query(query), body)
+    }
+    pub fn raw_post_with_id(&self, command: &str, query: &[(&str, &str)], body: &str) -> Result<Value, ConnectionError> {
+        self.post_parsed_with_id(command, &raw_query(query), body)
+    }
+
+    pub fn test_connection(&self) -> Result<(), ConnectionError> {
+        let _ = self.post("test", &[], "")?;
+        Ok(())
+    }
+
+    pub fn get_error_description(&self, error_id: i32) -> Result<String, ConnectionError> {
+        self.get_parsed("error_description", &[("id", &error_id)], "")?
+            .get("description").and_then(Value::as_str).map(String::from)
+            .ok_or_else(|| ConnectionError::Protocol("missing description".into()))
+    }
+
+    /// Warns once per connection if the server's major version differs from SUPPORTED_VERSION
+    pub fn server_version(&self) -> Result<String, ConnectionError> {
+        let version = self.get_parsed_optional("version", &[])?
+            .get("version").and_then(Value::as_str)
+            .ok_or_else(|| ConnectionError::Protocol("missing version".into()))?
+            .to_string();
+
+        let major = version.split('.').next().unwrap_or_default();
+        if major != Self::SUPPORTED_VERSION && !self.version_checked.swap(true, Ordering::Relaxed) {
+            warn!("Server API version {version} may be incompatible, supported major version is {}", Self::SUPPORTED_VERSION);
+        }
+        Ok(version)
+    }
+
+    /// Unix timestamp in seconds, taken from the "time" field of the `time` endpoint
+    pub fn get_server_time(&self) -> Result<u64, ConnectionError> {
+        self.get_parsed("time", &[], "")?
+            .get("time").and_then(Value::as_u64)
+            .ok_or_else(|| ConnectionError::Protocol("missing time".into()))
+    }
+
+    /// Fetched once and cached for the lifetime of the connection. Until it has been fetched,
+    /// send_message and send_request leave all the checking to the server.
+    pub fn get_server_config(&self) -> Result<ServerConfig, ConnectionError> {
+        let mut cached = self.server_config.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(config) = &*cached {
+            return Ok(config.clone());
+        }
+        let config = ServerConfig::from_json(&self.get_parsed_optional("config", &[])?);
+        *cached = Some(config.clone());
+        Ok(config)
+    }
+
+    fn cached_server_config(&self) -> ServerConfig {
+        self.server_config.lock().unwrap_or_else(PoisonError::into_inner).clone().unwrap_or_default()
+    }
+
+    pub fn get_players(&self) -> Result<Vec<Player>, ConnectionError> {
+        let x = self.get_parsed("players", &[], "")?;
+        let players = Player::list_from_json(&x, "players")?;
+
+        let mut cache = self.player_cache.lock().unwrap_or_else(PoisonError::into_inner);
+        cache.nicknames = players.iter().map(|p| (p.id, p.nickname.clone())).collect();
+        cache.updated = Some(Instant::now());
+        Ok(players)
+    }
+    /// Servers without the count query send the full list, which is then counted here
+    pub fn get_player_count(&self) -> Result<usize, ConnectionError> {
+        let x = self.get_parsed("players", &[("count", &true)], "")?;
+        count_from_json(&x, "players")
+    }
+    /// The flag says whether there are players past this page. Servers that don't paginate send the whole
+    /// list, recognised by being longer than `limit`, which is then sliced here. A page without "has_more"
+    /// is taken to be followed by another one when it is full.
+    pub fn get_players_page(&self, offset: usize, limit: usize) -> Result<(Vec<Player>, bool), ConnectionError> {
+        let x = self.get_parsed("players", &[("offset", &offset), ("limit", &limit)], "")?;
+        let players = Player::list_from_json(&x, "players")?;
+
+        if let Some(has_more) = x.get("has_more").and_then(Value::as_bool) {
+            return Ok((players, has_more));
+        }
+        if players.len() > limit {
+            let has_more = players.len() > offset.saturating_add(limit);
+            return Ok((players.into_iter().skip(offset).take(limit).collect(), has_more));
+        }
+        let has_more = players.len() == limit;
+        Ok((players, has_more))
+    }
+    /// Looks the id up in the players seen by the last get_players, without a request.
+    /// The list may be stale: players who left are still found and newcomers are not until the next get_players.
+    pub fn resolve_nickname(&self, id: u64) -> Option<String> {
+        let cache = self.player_cache.lock().unwrap_or_else(PoisonError::into_inner);
+        let expired = match (cache.updated, self.player_cache_ttl) {
+            (Some(updated), Some(ttl)) => updated.elapsed() > ttl,
+            _ => false,
+        };
+        if expired {
+            return None;
+        }
+        cache.nicknames.get(&id).cloned()
+    }
+    pub fn clear_player_cache(&self) {
+        *self.player_cache.lock().unwrap_or_else(PoisonError::into_inner) = PlayerCache::default();
+    }
+    /// Entries are "id:nickname" strings or {"player": "id:nickname", "score": n} objects, missing scores count as 0
+    pub fn get_leaderboard(&self) -> Result<Vec<(Player, u32)>, ConnectionError> {
+        let x = self.get_parsed_optional("leaderboard", &[])?;
+        let y = x.get("leaderboard").and_then(Value::as_array)
+                 .ok_or_else(|| ConnectionError::Protocol("missing leaderboard list".into()))?;
+
+        Ok(y.iter()
+            .filter_map(|z| {
+                let entry = z.as_str().or_else(|| z.get("player").and_then(Value::as_str))?;
+                let score = z.get("score").and_then(Value::as_u64).unwrap_or(0);
+                Some((Player::from_entry(entry).ok()?, score as u32))
+            })
+            .collect())
+    }
+    /// Servers that filter by state mark the response with "filtered": true and this costs one request.
+    /// Otherwise every player's state is queried separately, one request per player on top of the list.
+    pub fn get_players_filtered(&self, state: State) -> Result<Vec<Player>, ConnectionError> {
+        let state_id = state.id().ok_or_else(|| ConnectionError::Protocol("cannot filter by a disconnected state".into()))?;
+        let x = self.get_parsed("players", &[("state", &state_id)], "")?;
+        let players = Player::list_from_json(&x, "players")?;
+
+        if x.get("filtered").and_then(Value::as_bool) == Some(true) {
+            return Ok(players);
+        }
+
+        debug!("Server ignored the state filter, querying {} players one by one", players.len());
+        let mut res = vec![];
+        for player in players {
+            if self.get_player_state(player.id)? == state {
+                res.push(player);
+            }
+        }
+        Ok(res)
+    }
+    pub fn get_player(&self, id: u64) -> Result<Option<Player>, ConnectionError> {
+        Ok(self.get_players()?.into_iter().find(|p| p.id == id))
+    }
+
+    pub fn get_stats(&self) -> Result<PlayerStats, ConnectionError> {
+        Ok(PlayerStats::from_json(&self.get_parsed_with_id("stats", &[], "")?))
+    }
+
+    pub fn get_nickname(&self) -> Result<String, ConnectionError> {
+        Ok(self.require_registered()?.nickname.clone())
+    }
+    pub fn id(&self) -> Option<u64> {
+        self.info.as_ref().map(|info| info.id)
+    }
+    pub fn player_id(&self) -> Option<u64> {
+        self.info.as_ref().map(|info| info.player_id)
+    }
+    pub fn is_registered(&self) -> bool {
+        self.info.is_some()
+    }
+    /// Based on the stored state, call get_state first for the server's view
+    pub fn is_in_game(&self) -> bool {
+        matches!(self.state, State::Playing)
+    }
+
+    /// Checks that the server is reachable and answers with a well-formed envelope, no registration needed.
+    /// An error envelope from the server still counts as a healthy answer.
+    pub fn ping(&self) -> Result<(), ConnectionError> {
+        let x = self.get("error_description", &[("id", &0)], "")?;
+        match Connection::parse(&x) {
+            Err(e) if e.id == Error::LOCAL_ERROR_ID => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn heartbeat(&mut self) -> Result<(), ConnectionError> {
+        let _ = self.post_synced_with_id("heartbeat", &[], "")?;
+        Ok(())
+    }
+
+    /// Sends a heartbeat every `interval` on a background thread, locking the connection only for the call itself.
+    /// The thread exits once every other handle to the connection is dropped or a heartbeat fails.
+    pub fn spawn_heartbeat(connection: &Arc<Mutex<Connection>>, interval: Duration) -> JoinHandle<()> {
+        let connection = Arc::downgrade(connection);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+
+            let Some(connection) = connection.upgrade() else { break };
+            let res = match connection.lock() {
+                Ok(mut connection) => connection.heartbeat(),
+                Err(_) => break,
+            };
+            if let Err(e) = res {
+                warn!("Heartbeat failed: {e}");
+                break;
+            }
+        })
+    }
+
+    /// Failing to reach the server is reported as State::Disconnected rather than as an error
+    pub fn get_state(&mut self) -> Result<State, ConnectionError> {
+        let state = match self.get_parsed_with_id("state", &[], "") {
+            Ok(x) => {
+                // Session context is optional, missing fields keep what we already know
+                if let Some(opponent) = opponent_from_json(&x) {
+                    self.opponent = Some(opponent);
+                }
+                if let Some(game_id) = x.get("game_id").and_then(Value::as_u64) {
+                    self.current_game = Some(game_id);
+                }
+                State::from_json(&x)?
+            }
+            Err(ConnectionError::Http(e)) if e.is_connect() || e.is_timeout() => {
+                debug!("Server unreachable: {e}");
+                State::Disconnected(DisconnectReason::NetworkError)
+            }
+            Err(e) => return Err(e),
+        };
+        self.set_state(state.clone());
+        Ok(state)
+    }
+    pub fn get_stored_state(&self) -> State {
+        self.state.clone()
+    }
+
+    pub fn get_player_state(&self, id: u64) -> Result<State, ConnectionError> {
+        State::from_json(&self.get_parsed("state", &[("player", &id)], "")?)
+    }
+
+    pub fn disconnect(&self) {
+        
+    }
+
+    pub fn register(&mut self, nickname: String) -> Result<(), ConnectionError> {
+        self.register_detailed(&nickname)?;
+        Ok(())
+    }
+    pub fn register_detailed(&mut self, nickname: &str) -> Result<Registration, ConnectionError> {
+        validate_nickname(nickname).map_err(ConnectionError::Protocol)?;
+        let resp = self.post_parsed("register", &[("name", &nickname)], "")?;
+        let registration = Registration::from_json(&resp)?;
+        self.info = Some(RegPlayerInfo::from(&registration));
+        self.transition(&resp, State::Idle);
+
+        Ok(registration)
+    }
+    /// Like register, but a nickname that is already taken hands back that player's session,
+    /// marked "resumed" in the response, instead of creating a second player. Servers that insist on
+    /// unique nicknames answer with their usual error, returned as ConnectionError::Server.
+    /// A connection registered under another nickname is refused, unregister it first.
+    pub fn register_or_resume(&mut self, nickname: &str) -> Result<State, ConnectionError> {
+        if let Some(info) = &self.info {
+            if info.nickname != nickname {
+                return Err(ConnectionError::Protocol(format!("already registered as {}", info.nickname)));
+            }
+            return self.get_state();
+        }
+        validate_nickname(nickname).map_err(ConnectionError::Protocol)?;
+
+        let resp = self.post_parsed("register", &[("name", &nickname), ("resume", &true)], "")?;
+        self.info = Some(RegPlayerInfo::from(&Registration::from_json(&resp)?));
+        if resp.get("resumed").and_then(Value::as_bool) != Some(true) {
+            self.transition(&resp, State::Idle);
+            return Ok(self.state.clone());
+        }
+
+        debug!("Resumed the session of {nickname}");
+        let state = self.get_state()?;
+        if state == State::Playing {
+            self.sync_moves()?;
+        }
+        Ok(state)
+    }
+
+    /// A nickname another player has is refused by the server with Error::NICKNAME_TAKEN_ID
+    pub fn change_nickname(&mut self, new: &str) -> Result<(), ConnectionError> {
+        if self.require_registered()?.nickname == new {
+            return Ok(());
+        }
+        validate_nickname(new).map_err(ConnectionError::Protocol)?;
+
+        let _ = self.post_synced_with_id("rename", &[("name", &new)], "")?;
+
+        if let Some(info) = &mut self.info {
+            info.nickname = new.to_string();
+        }
+        Ok(())
+    }
+
+    /// Restores a session saved from id(), player_id() and get_nickname() without registering again
+    pub fn reconnect(&mut self, id: u64, player_id: u64, nickname: &str) -> Result<State, ConnectionError> {
+        self.info = Some(RegPlayerInfo::new(nickname, id, player_id));
+        match self.get_state() {
+            Ok(State::Playing) => {
+                if let Err(e) = self.sync_moves() {
+                    warn!("Reconnected into a game but failed to fetch its moves: {e}");
+                }
+                Ok(State::Playing)
+            }
+            Ok(state) => Ok(state),
+            Err(e) => {
+                self.info = None;
+                self.set_state(State::Registration);
+                Err(e)
+            }
+        }
+    }
+
+    /// Re-syncs the saved session until get_state reaches the server, waiting base_delay * 2^attempt in between
+    pub fn reconnect_loop(&mut self, max_attempts: u32, base_delay: Duration) -> Result<State, ConnectionError> {
+        self.require_registered()?;
+
+        let mut last = ConnectionError::Protocol("no reconnect attempts made".into());
+        for attempt in 0..max_attempts {
+            if attempt > 0 {
+                std::thread::sleep(base_delay * 2u32.saturating_pow(attempt - 1));
+            }
+            match self.get_state() {
+                Ok(State::Disconnected(reason)) => last = ConnectionError::Protocol(format!("disconnected: {reason}")),
+                Ok(state) => return Ok(state),
+                Err(e) => last = e,
+            }
+        }
+        Err(last)
+    }
+
+    pub fn unregister(&mut self) -> Result<(), ConnectionError> {
+        let resp = self.post_parsed_with_id("unregister", &[], "");
+        // Reset even if the server rejected the call so the connection can register again
+        self.info = None;
+        self.opponent = None;
+        self.current_game = None;
+        self.set_state(State::Registration);
+
+        resp?;
+        Ok(())
+    }
+
+    /// Ends the game, leaves matchmaking and unregisters, in that order. Every step is attempted
+    /// even when an earlier one failed, the first error is the one returned.
+    pub fn close(mut self) -> Result<(), ConnectionError> {
+        let mut first_error = None;
+        if self.state == State::Playing {
+            first_error = self.end_game().err();
+        }
+        if self.state == State::Searching {
+            first_error = first_error.or(self.idle().err());
+        }
+        if self.info.is_some() {
+            first_error = first_error.or(self.unregister().err());
+        }
+        first_error.map_or(Ok(()), Err)
+    }
+
+    pub fn search(&mut self) -> Result<State, ConnectionError> {
+        self.search_with(SearchPrefs::default())
+    }
+    pub fn search_with(&mut self, prefs: SearchPrefs) -> Result<State, ConnectionError> {
+        if self.state == State::Playing {
+            return Err(ConnectionError::Protocol("cannot search while playing".into()));
+        }
+        if let (Some(min), Some(max)) = (prefs.min_rating, prefs.max_rating) {
+            if min > max {
+                return Err(ConnectionError::Protocol(format!("min_rating {min} is above max_rating {max}")));
+            }
+        }
+        let x = self.post_synced_with_id("search", &prefs.query(), "")?;
+        self.transition(&x, State::Searching);
+
+        Ok(self.state.clone())
+    }
+    /// Polls get_state until a game starts (true) or `timeout` passes while still searching (false).
+    /// Unreachable servers are polled again, leaving the search on the server's side is an error.
+    pub fn wait_until_matched(&mut self, timeout: Duration) -> Result<bool, ConnectionError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.get_state()? {
+                State::Playing => return Ok(true),
+                State::Searching | State::Disconnected(_) => {}
+                state => return Err(ConnectionError::Protocol(format!("stopped searching, state is {state:?}"))),
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(false);
+            }
+            std::thread::sleep(self.match_poll_interval.min(remaining));
+        }
+    }
+    pub fn idle(&mut self) -> Result<State, ConnectionError> {
+        let x = self.post_synced_with_id("idle", &[], "")?;
+        self.transition(&x, State::Idle);
+
+        Ok(self.state.clone())
+    }
+    /// The server has no separate endpoint for leaving the queue, so this is `idle` restricted to State::Searching
+    pub fn cancel_search(&mut self) -> Result<State, ConnectionError> {
+        if self.state != State::Searching {
+            return Err(ConnectionError::Protocol("cannot cancel search while not searching".into()));
+        }
+        self.idle()
+    }
+
+    pub fn send_request(&mut self, send_to: u64) -> Result<RequestOutcome, ConnectionError> {
+        if let (Some(cooldown), Some(sent)) = (self.cached_server_config().request_cooldown, self.last_request_sent) {
+            if sent.elapsed() < cooldown {
+                return Err(ConnectionError::Protocol(format!("requests are limited to one per {cooldown:?}")));
+            }
+        }
+        let x = self.post_idempotent_with_id("requests", &[("send_to", &send_to)], "")?;
+        self.last_request_sent = Some(Instant::now());
+
+        let outcome = RequestOutcome::from_json(&x)?;
+        if outcome == RequestOutcome::GameStarted {
+            self.start_game(&x);
+        }
+        Ok(outcome)
+    }
+    pub fn send_request_to_nickname(&mut self, nickname: &str) -> Result<RequestOutcome, ConnectionError> {
+        let ids: Vec<u64> = self.get_players()?.into_iter().filter(|p| p.nickname == nickname).map(|p| p.id).collect();
+        match ids[..] {
+            [id] => self.send_request(id),
+            [] => Err(ConnectionError::Protocol(format!("player {nickname} not found"))),
+            _ => Err(ConnectionError::Protocol(format!("nickname {nickname} is ambiguous, matching ids: {ids:?}"))),
+        }
+    }
+    /// The server answers with an error if there is no outstanding request to `send_to`
+    pub fn withdraw_request(&mut self, send_to: u64) -> Result<(), ConnectionError> {
+        if self.state == State::Playing {
+            return Err(ConnectionError::Protocol("cannot withdraw a request while playing".into()));
+        }
+        let _ = self.post_synced_with_id("requests", &[("withdraw", &send_to)], "")?;
+        Ok(())
+    }
+    pub fn respond_request(&mut self, from: u64, accept: bool) -> Result<(), ConnectionError> {
+        let x = self.post_synced_with_id("requests", &[("respond_to", &from), ("accept", &accept)], "")?;
+
+        if accept && RequestOutcome::from_json(&x)? == RequestOutcome::GameStarted {
+            self.start_game(&x);
+        }
+        Ok(())
+    }
+    pub fn get_requests(&mut self) -> Result<Vec<Player>, ConnectionError> {
+        let x = self.get_synced_with_id("requests", &[], "")?;
+        Ok(Player::list_from_json(&x, "requests")?)
+    }
+    /// Servers that only track incoming requests ignore the direction and don't echo it back,
+    /// their list would be the incoming one so nothing is returned instead
+    pub fn get_outgoing_requests(&mut self) -> Result<Vec<Player>, ConnectionError> {
+        let x = self.get_synced_with_id("requests", &[("direction", &"outgoing")], "")?;
+        if x.get("direction").and_then(Value::as_str) != Some("outgoing") {
+            warn!("Server doesn't track outgoing requests");
+            return Ok(vec![]);
+        }
+        Ok(Player::list_from_json(&x, "requests")?)
+    }
+    pub fn pending_requests_count(&mut self) -> Result<usize, ConnectionError> {
+        let x = self.get_synced_with_id("requests", &[("count", &true)], "")?;
+        count_from_json(&x, "requests")
+    }
+
+    /// Opens a game only reachable through the returned code. The game starts once someone joins it,
+    /// which shows up in get_state like an accepted request does.
+    pub fn create_private_game(&mut self) -> Result<String, ConnectionError> {
+        if self.state == State::Playing {
+            return Err(ConnectionError::Protocol("cannot create a game while playing".into()));
+        }
+        let x = self.post_synced_with_id("private", &[], "")?;
+        x.get("code").and_then(Value::as_str).map(String::from)
+            .ok_or_else(|| ConnectionError::Protocol("missing join code".into()))
+    }
+    pub fn join_private_game(&mut self, code: &str) -> Result<RequestOutcome, ConnectionError> {
+        if self.state == State::Playing {
+            return Err(ConnectionError::Protocol("cannot join a game while playing".into()));
+        }
+        if code.is_empty() {
+            return Err(ConnectionError::Protocol("join code is empty".into()));
+        }
+        let x = self.post_synced_with_id("private", &[("code", &code)], "").map_err(|e| match e {
+            ConnectionError::Server(e) if e.id == Error::INVALID_JOIN_CODE_ID => ConnectionError::Protocol(format!("join code {code} is invalid or expired: {e}")),
+            e => e,
+        })?;
+
+        let outcome = RequestOutcome::from_json(&x)?;
+        if outcome == RequestOutcome::GameStarted {
+            self.start_game(&x);
+        }
+        Ok(outcome)
+    }
+
+    /// Sent as {"text": message}, returns the id the server gave the message if it reports one
+    pub fn send_message(&mut self, message: String) -> Result<Option<u64>, ConnectionError> {
+        if self.state != State::Playing {
+            return Err(ConnectionError::Protocol("cannot send messages while not playing".into()));
+        }
+        self.check_message_length(&message)?;
+        let body = message_body(&message).to_string();
+        let x = self.post_idempotent_with_id("messages", &[], body.as_str())?;
+
+        Ok(x.get("message_id").and_then(Value::as_u64))
+    }
+    fn check_message_length(&self, message: &str) -> Result<(), ConnectionError> {
+        match self.cached_server_config().max_message_length {
+            Some(max) if message.chars().count() > max => Err(ConnectionError::Protocol(format!("message is longer than {max} characters"))),
+            _ => Ok(()),
+        }
+    }
+    /// The message is the whole body, for servers that don't read JSON messages
+    pub fn send_raw_message(&mut self, message: &str) -> Result<(), ConnectionError> {
+        if self.state != State::Playing {
+            return Err(ConnectionError::Protocol("cannot send messages while not playing".into()));
+        }
+        let _ = self.post_idempotent_with_id("messages", &[], message)?;
+
+        Ok(())
+    }
+    /// The batched request is all-or-nothing. Servers that reject it get the lines one by one instead,
+    /// which is not atomic: on failure the error says how many lines were already sent.
+    pub fn send_messages(&mut self, messages: &[String]) -> Result<(), ConnectionError> {
+        if self.state != State::Playing {
+            return Err(ConnectionError::Protocol("cannot send messages while not playing".into()));
+        }
+        for message in messages {
+            self.check_message_length(message)?;
+        }
+
+        let body = Value::from_iter(messages.iter().map(|m| message_body(m))).to_string();
+        match self.post_synced_with_id("messages", &[("batch", &true)], body.as_str()) {
+            Err(ConnectionError::Server(e)) => debug!("Batched messages rejected, sending one by one: {e}"),
+            res => return res.map(|_| ()),
+        }
+
+        for (sent, message) in messages.iter().enumerate() {
+            if let Err(e) = self.send_message(message.clone()) {
+                return Err(ConnectionError::Protocol(format!("sent {sent} of {} messages: {e}", messages.len())));
+            }
+        }
+        Ok(())
+    }
+    pub fn get_messages(&mut self) -> Result<Vec<String>, ConnectionError> {
+        let x = self.get_synced_with_id("messages", &[], "")?;
+        messages_from_json(&x)
+    }
+
+    /// Oldest first unless `newest_first`. With `dedup` a message resent by the server, recognised by an "index"
+    /// seen before, is dropped. Entries without an index are always kept, so repeated lines are never collapsed.
+    pub fn get_messages_ordered(&mut self, newest_first: bool, dedup: bool) -> Result<Vec<String>, ConnectionError> {
+        let x = self.get_synced_with_id("messages", &[], "")?;
+        let entries = x.get("messages").and_then(Value::as_array)
+                       .ok_or_else(|| ConnectionError::Protocol("missing messages list".into()))?;
+
+        let mut seen = HashSet::new();
+        let mut messages = vec![];
+        for entry in entries {
+            let Some(message) = ChatMessage::from_json(entry) else { continue };
+            if dedup && entry.get("index").and_then(Value::as_u64).is_some_and(|index| !seen.insert(index)) {
+                continue;
+            }
+            messages.push(message);
+        }
+
+        if newest_first {
+            messages.reverse();
+        }
+        Ok(messages.into_iter().map(|m| m.text).collect())
+    }
+
+    /// The total is the server's message count, compare it with what was seen to detect gaps
+    pub fn get_messages_with_total(&mut self) -> Result<(Vec<String>, usize), ConnectionError> {
+        let x = self.get_synced_with_id("messages", &[], "")?;
+        let messages = messages_from_json(&x)?;
+        let total = x.get("total").and_then(Value::as_u64).map_or(messages.len(), |t| t as usize);
+        Ok((messages, total))
+    }
+    pub fn get_chat(&mut self) -> Result<Vec<ChatMessage>, ConnectionError> {
+        let x = self.get_synced_with_id("messages", &[], "")?;
+        chat_from_json(&x)
+    }
+
+    /// `after_index` is the number of messages already seen; the returned index is the cursor for the next call.
+    /// Servers that support `since` report the cursor in "index", otherwise the full history is sliced locally.
+    pub fn get_messages_since(&mut self, after_index: usize) -> Result<(Vec<String>, usize), ConnectionError> {
+        let x = self.get_synced_with_id("messages", &[("since", &after_index)], "")?;
+        let messages = messages_from_json(&x)?;
+
+        match x.get("index").and_then(Value::as_u64) {
+            Some(index) => Ok((messages, index as usize)),
+            None => {
+                let index = messages.len();
+                Ok((messages.into_iter().skip(after_index).collect(), index))
+            }
+        }
+    }
+
+    /// Yields the existing history first, then each new message as it arrives
+    pub fn messages_iter(&mut self, poll_interval: Duration) -> MessagesIter<'_> {
+        MessagesIter {
+            connection: self,
+            poll_interval,
+            cursor: 0,
+            pending: VecDeque::new(),
+            failed: false,
+        }
+    }
+
+    /// Waits up to `timeout` for messages. Servers that answer empty before that are polled again
+    /// with whatever time is left, at most once per LONG_POLL_REPOLL_DELAY, see LONG_POLL_MARGIN.
+    pub fn wait_for_messages(&mut self, timeout: Duration) -> Result<Vec<String>, ConnectionError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(vec![]);
+            }
+
+            // Rounded down, the server is never asked to hold the request past the deadline
+            let query: &Query = &[("wait", &remaining.as_secs())];
+            match self.get_parsed_with_id_timeout("messages", query, remaining + Self::LONG_POLL_MARGIN) {
+                Ok(x) => {
+                    self.sync_state(&x);
+                    let messages = messages_from_json(&x)?;
+                    if !messages.is_empty() {
+                        return Ok(messages);
+                    }
+                }
+                Err(ConnectionError::Http(e)) if e.is_timeout() => return Ok(vec![]),
+                Err(e) => return Err(e),
+            }
+            Self::pause_before_repoll(deadline);
+        }
+    }
+
+    fn pause_before_repoll(deadline: Instant) {
+        std::thread::sleep(deadline.saturating_duration_since(Instant::now()).min(Self::LONG_POLL_REPOLL_DELAY));
+    }
+
+    /// The move is sent as the body, its format is up to the game being played
+    pub fn make_move(&mut self, move_data: &str) -> Result<(), ConnectionError> {
+        if self.state != State::Playing {
+            return Err(ConnectionError::Protocol("cannot move while not playing".into()));
+        }
+        let _ = self.post_idempotent_with_id("move", &[], move_data)?;
+        Ok(())
+    }
+    /// The opponent's moves in this game so far, oldest first
+    pub fn get_moves(&mut self) -> Result<Vec<String>, ConnectionError> {
+        if self.state != State::Playing {
+            return Err(ConnectionError::Protocol("cannot get moves while not playing".into()));
+        }
+        let x = self.get_synced_with_id("move", &[], "")?;
+        moves_from_json(&x)
+    }
+    /// Refetches the game we are playing, e.g. after reconnect, and returns its moves so far.
+    /// wait_for_move only reports moves made after these.
+    pub fn sync_game(&mut self) -> Result<Vec<String>, ConnectionError> {
+        if self.get_state()? != State::Playing {
+            return Err(ConnectionError::Protocol("not in a game".into()));
+        }
+        self.sync_moves()
+    }
+    // For callers that have just seen get_state report Playing
+    fn sync_moves(&mut self) -> Result<Vec<String>, ConnectionError> {
+        let moves = self.get_moves()?;
+        self.move_cursor = moves.len();
+        Ok(moves)
+    }
+    /// Returns the opponent's next move not yet returned by an earlier call, or None once `timeout` passes.
+    /// Fails as soon as the server reports that the game is over.
+    pub fn wait_for_move(&mut self, timeout: Duration) -> Result<Option<String>, ConnectionError> {
+        if self.state != State::Playing {
+            return Err(ConnectionError::Protocol("cannot wait for a move while not playing".into()));
+        }
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+
+            let cursor = self.move_cursor;
+            let query: &Query = &[("wait", &remaining.as_secs()), ("since", &cursor)];
+            let x = match self.get_parsed_with_id_timeout("move", query, remaining + Self::LONG_POLL_MARGIN) {
+                Ok(x) => x,
+                Err(ConnectionError::Http(e)) if e.is_timeout() => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            self.sync_state(&x);
+            if self.state != State::Playing {
+                return Err(ConnectionError::Protocol("game ended while waiting for a move".into()));
+            }
+
+            // Servers that support `since` report the cursor in "index", the others send every move
+            let moves = moves_from_json(&x)?;
+            let skip = if x.get("index").is_some() { 0 } else { cursor };
+            if let Some(next) = moves.into_iter().nth(skip) {
+                self.move_cursor += 1;
+                return Ok(Some(next));
+            }
+            Self::pause_before_repoll(deadline);
+        }
+    }
+
+    pub fn end_game(&mut self) -> Result<GameResult, ConnectionError> {
+        let x = self.post_synced_with_id("end_game", &[], "")?;
+        self.finish_game();
+        self.transition(&x, State::Idle);
+        Ok(GameResult::from_json(&x))
+    }
+    /// Concedes the game, unlike end_game which concludes it normally
+    pub fn forfeit(&mut self) -> Result<(), ConnectionError> {
+        if self.state != State::Playing {
+            return Err(ConnectionError::Protocol("cannot forfeit while not playing".into()));
+        }
+        let x = self.post_synced_with_id("end_game", &[("forfeit", &true)], "")?;
+        self.finish_game();
+        self.transition(&x, State::Idle);
+        Ok(())
+    }
+
+    /// Spectating doesn't need registration, the game is addressed by id instead of our player id
+    pub fn spectate(&mut self, game_id: u64) -> Result<(), ConnectionError> {
+        let _ = self.post_parsed("spectate", &[("game", &game_id)], "")?;
+        self.spectating = Some(game_id);
+        Ok(())
+    }
+    pub fn get_spectated_messages(&self) -> Result<Vec<String>, ConnectionError> {
+        let game_id = self.spectating.ok_or_else(|| ConnectionError::Protocol("not spectating".into()))?;
+        let x = self.get_parsed("messages", &[("game", &game_id)], "")?;
+        messages_from_json(&x)
+    }
+
+    /// Games currently being played, as candidates for spectate. Malformed entries are skipped.
+    pub fn get_active_games(&self) -> Result<Vec<GameSummary>, ConnectionError> {
+        let x = self.get_parsed("games", &[], "")?;
+        let games = x.get("games").and_then(Value::as_array)
+                     .ok_or_else(|| ConnectionError::Protocol("missing games list".into()))?;
+        Ok(games.iter().filter_map(GameSummary::from_json).collect())
+    }
+
+    /// Transcript of any finished game, whether or not we took part in it.
+    /// Unknown ids come back as the server's error.
+    pub fn get_game_history(&self, game_id: u64) -> Result<Vec<ChatMessage>, ConnectionError> {
+        let x = self.get_parsed("history", &[("game", &game_id)], "")?;
+        if !x.get("messages").is_some_and(Value::is_array) {
+            return Err(ConnectionError::Protocol(format!("no history available for game {game_id}")));
+        }
+        chat_from_json(&x)
+    }
+
+    pub fn opponent(&self) -> Option<&Player> {
+        self.opponent.as_ref()
+    }
+    pub fn current_game(&self) -> Option<u64> {
+        self.current_game
+    }
+
+    /// Compares the server's current state, requests and chat against the previous call.
+    /// The first call has nothing to compare against and reports everything as new.
+    pub fn poll_events(&mut self) -> Result<Vec<Event>, ConnectionError> {
+        // Everything is fetched before the snapshot is touched, so a failed poll is reported in full next time.
+        // An unreachable server is reported as a state change, with the requests left as they were.
+        let state = self.get_state()?;
+        let requests = match state {
+            State::Disconnected(_) => self.snapshot.requests.clone(),
+            _ => self.get_requests()?,
+        };
+        let state_changed = self.snapshot.state.as_ref() != Some(&state);
+        let messages = if state == State::Playing {
+            let cursor = if state_changed { 0 } else { self.snapshot.message_cursor };
+            Some(self.get_messages_since(cursor)?)
+        }
+        else {
+            None
+        };
+
+        let mut events = vec![];
+        if state_changed {
+            events.push(Event::StateChanged(state.clone()));
+            if state == State::Playing {
+                events.push(Event::GameStarted);
+            }
+            else if self.snapshot.state == Some(State::Playing) {
+                events.push(Event::GameEnded);
+            }
+        }
+        self.snapshot.state = Some(state);
+
+        for old in &self.snapshot.requests {
+            if !requests.iter().any(|p| p.id == old.id) {
+                events.push(Event::RequestWithdrawn(old.id));
+            }
+        }
+        for new in &requests {
+            if !self.snapshot.requests.iter().any(|p| p.id == new.id) {
+                events.push(Event::NewRequest(new.clone()));
+            }
+        }
+        self.snapshot.requests = requests;
+
+        if let Some((messages, cursor)) = messages {
+            self.snapshot.message_cursor = cursor;
+            events.extend(messages.into_iter().map(Event::NewMessage));
+        }
+
+        Ok(events)
+    }
+
+    /// Calls poll_events every `interval` and hands each event to `handler` until it returns Stop.
+    /// A failed poll doesn't end the loop, it is passed on as Event::Error for the handler to decide.
+    pub fn run_loop(&mut self, mut handler: impl FnMut(Event) -> LoopControl, interval: Duration) -> Result<(), ConnectionError> {
+        self.require_registered()?;
+        loop {
+            let events = self.poll_events().unwrap_or_else(|e| vec![Event::Error(e)]);
+            for event in events {
+                if handler(event) == LoopControl::Stop {
+                    return Ok(());
+                }
+            }
+            std::thread::sleep(interval);
+        }
+    }
+}
+
+/// Dropping a registered connection blocks on a best-effort unregister so no ghost player is left behind.
+/// Call disable_auto_unregister first to keep the session alive, e.g. to reconnect to it later.
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if self.auto_unregister && self.info.is_some() {
+            if let Err(e) = self.unregister() {
+                warn!("Failed to unregister on drop: {e}");
+            }
+        }
+    }
+}
+
+// Connection is Send + Sync, so it can be moved between threads or put behind Arc<Mutex<_>>
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Connection>();
+};
+
+/// A cloneable handle to one Connection shared between threads. Each call locks the connection
+/// for its whole duration, so a long poll on one thread delays calls from the others.
+#[derive(Clone)]
+pub struct SharedConnection {
+    inner: Arc<Mutex<Connection>>,
+}
+
+impl SharedConnection {
+    pub fn new(connection: Connection) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(connection)),
+        }
+    }
+
+    /// A panic on another thread can't leave the connection half-updated, so a poisoned lock is still usable
+    pub fn with<R>(&self, f: impl FnOnce(&mut Connection) -> R) -> R {
+        f(&mut self.inner.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    pub fn spawn_heartbeat(&self, interval: Duration) -> JoinHandle<()> {
+        Connection::spawn_heartbeat(&self.inner, interval)
+    }
+
+    pub fn get_state(&self) -> Result<State, ConnectionError> {
+        self.with(|c| c.get_state())
+    }
+    pub fn get_stored_state(&self) -> State {
+        self.with(|c| c.get_stored_state())
+    }
+    pub fn get_players(&self) -> Result<Vec<Player>, ConnectionError> {
+        self.with(|c| c.get_players())
+    }
+    pub fn register(&self, nickname: String) -> Result<(), ConnectionError> {
+        self.with(|c| c.register(nickname))
+    }
+    pub fn unregister(&self) -> Result<(), ConnectionError> {
+        self.with(|c| c.unregister())
+    }
+    pub fn search(&self) -> Result<State, ConnectionError> {
+        self.with(|c| c.search())
+    }
+    pub fn idle(&self) -> Result<State, ConnectionError> {
+        self.with(|c| c.idle())
+    }
+    pub fn send_request(&self, send_to: u64) -> Result<RequestOutcome, ConnectionError> {
+        self.with(|c| c.send_request(send_to))
+    }
+    pub fn respond_request(&self, from: u64, accept: bool) -> Result<(), ConnectionError> {
+        self.with(|c| c.respond_request(from, accept))
+    }
+    pub fn get_requests(&self) -> Result<Vec<Player>, ConnectionError> {
+        self.with(|c| c.get_requests())
+    }
+    pub fn send_message(&self, message: String) -> Result<Option<u64>, ConnectionError> {
+        self.with(|c| c.send_message(message))
+    }
+    pub fn get_messages(&self) -> Result<Vec<String>, ConnectionError> {
+        self.with(|c| c.get_messages())
+    }
+    pub fn end_game(&self) -> Result<GameResult, ConnectionError> {
+        self.with(|c| c.end_game())
+    }
+}
+
+pub struct MessagesIter<'a> {
+    connection: &'a mut Connection,
+    poll_interval: Duration,
+    cursor: usize,
+    pending: VecDeque<String>,
+    // Set after a failed poll so that the next one waits poll_interval first
+    failed: bool,
+}
+
+impl Iterator for MessagesIter<'_> {
+    type Item = Result<String, ConnectionError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pending.is_empty() {
+            if std::mem::take(&mut self.failed) {
+                std::thread::sleep(self.poll_interval);
+            }
+            let (messages, cursor) = match self.connection.get_messages_since(self.cursor) {
+                Ok(x) => x,
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
+                }
+            };
+            self.cursor = cursor;
+            self.pending.extend(messages);
+
+            if self.pending.is_empty() {
+                std::thread::sleep(self.poll_interval);
+            }
+        }
+        self.pending.pop_front().map(Ok)
+    }
+}
+
+
+pub struct AsyncConnection {
+    state: State,
+    info: Option<RegPlayerInfo>,
+    base_url: String,
+    client: reqwest::Client,
+}
+
+impl AsyncConnection {
+    fn url(&self, command: &str) -> String {
+        format!("{}/{}", self.base_url, command.trim_start_matches('/'))
+    }
+
+    fn require_registered(&self) -> Result<&RegPlayerInfo, ConnectionError> {
+        self.info.as_ref().ok_or_else(|| ConnectionError::Protocol("not registered".into()))
+    }
+
+    async fn get(&self, command: &str, query: &Query<'_>, body: &str) -> Result<Value, ConnectionError> {
+        let query = query_pairs(query);
+        debug!("GET /{command} query: {query:?}");
+
+        let resp = self.client.get(self.url(command)).query(&query).body(body.to_string()).send().await?;
+        let resp: Value = match resp.status() {
+            status if status.is_success() => resp.json().await?,
+            status => status_error(status, &resp.text().await?)?,
+        };
+        trace!("Response: {resp}");
+        Ok(resp)
+    }
+    async fn get_parsed(&self, command: &str, query: &Query<'_>, body: &str) -> Result<Value, ConnectionError> {
+        let x = self.get(command, query, body).await?;
+        Ok(Connection::parse(&x)?.clone())
+    }
+    async fn get_parsed_with_id(&self, command: &str, query: &Query<'_>, body: &str) -> Result<Value, ConnectionError> {
+        self.get_parsed(format!("{}/{command}", self.require_registered()?.id).as_str(), query, body).await
+    }
+
+    async fn post(&self, command: &str, query: &Query<'_>, body: &str) -> Result<Value, ConnectionError> {
+        let query = query_pairs(query);
+        debug!("POST /{command} query: {query:?}");
+
+        let mut request = self.client.post(self.url(command)).query(&query);
+        if !body.is_empty() {
+            request = request.header(reqwest::header::CONTENT_TYPE, default_content_type(body)).body(body.to_string());
+        }
+        let resp = request.send().await?;
+        let resp: Value = match resp.status() {
+            status if status.is_success() => resp.json().await?,
+            status => status_error(status, &resp.text().await?)?,
+        };
+        trace!("Response: {resp}");
+        Ok(resp)
+    }
+    async fn post_parsed(&self, command: &str, query: &Query<'_>, body: &str) -> Result<Value, ConnectionError> {
+        let x = self.post(command, query, body).await?;
+        Ok(Connection::parse(&x)?.clone())
+    }
+    async fn post_parsed_with_id(&self, command: &str, query: &Query<'_>, body: &str) -> Result<Value, ConnectionError> {
+        self.post_parsed(format!("{}/{command}", self.require_registered()?.id).as_str(), query, body).await
+    }
+}
+
+impl AsyncConnection {
+    pub fn new(url: &str) -> Self {
+        Self {
+            state: State::Registration,
+            info: None,
+            base_url: normalize_base_url(url),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub async fn get_state(&mut self) -> Result<State, ConnectionError> {
+        let state = State::from_json(&self.get_parsed_with_id("state", &[], "").await?)?;
+        self.state = state.clone();
+        Ok(state)
+    }
+    pub fn get_stored_state(&self) -> State {
+        self.state.clone()
+    }
+
+    pub async fn register(&mut self, nickname: String) -> Result<(), ConnectionError> {
+        validate_nickname(&nickname).map_err(ConnectionError::Protocol)?;
+        let resp = self.post_parsed("register", &[("name", &nickname)], "").await?;
+        self.info = Some(RegPlayerInfo::from(&Registration::from_json(&resp)?));
+        self.state = State::Idle;
+
+        Ok(())
+    }
+
+    pub async fn search(&mut self) -> Result<(), ConnectionError> {
+        if self.state == State::Playing {
+            return Err(ConnectionError::Protocol("cannot search while playing".into()));
+        }
+        let _ = self.post_parsed_with_id("search", &[], "").await?;
+        self.state = State::Searching;
+
+        Ok(())
+    }
+    pub async fn idle(&mut self) -> Result<(), ConnectionError> {
+        let _ = self.post_parsed_with_id("idle", &[], "").await?;
+        self.state = State::Idle;
+
+        Ok(())
+    }
+
+    pub async fn send_request(&mut self, send_to: u64) -> Result<RequestOutcome, ConnectionError> {
+        let x = self.post_parsed_with_id("requests", &[("send_to", &send_to)], "").await?;
+
+        let outcome = RequestOutcome::from_json(&x)?;
+        if outcome == RequestOutcome::GameStarted {
+            self.state = State::Playing;
+        }
+        Ok(outcome)
+    }
+    pub async fn get_requests(&mut self) -> Result<Vec<Player>, ConnectionError> {
+        let x = self.get_parsed_with_id("requests", &[], "").await?;
+        Ok(Player::list_from_json(&x, "requests")?)
+    }
+
+    pub async fn send_message(&mut self, message: String) -> Result<Option<u64>, ConnectionError> {
+        if self.state != State::Playing {
+            return Err(ConnectionError::Protocol("cannot send messages while not playing".into()));
+        }
+        let body = message_body(&message).to_string();
+        let x = self.post_parsed_with_id("messages", &[], body.as_str()).await?;
+
+        Ok(x.get("message_id").and_then(Value::as_u64))
+    }
+    pub async fn get_messages(&mut self) -> Result<Vec<String>, ConnectionError> {
+        let x = self.get_parsed_with_id("messages", &[], "").await?;
+        messages_from_json(&x)
+    }
+
+    pub async fn end_game(&mut self) -> Result<(), ConnectionError> {
+        let _ = self.post_parsed_with_id("end_game", &[], "").await?;
+        self.state = State::Idle;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,183 @@
+// A tiny HTTP server on a random local port for tests. Every request is recorded and answered
+// by the handler, one connection per request so that nothing depends on keep-alive.
+use std::{io::{BufRead, BufReader, Read, Write}, net::{TcpListener, TcpStream}, sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}}, thread::{self, JoinHandle}};
+use flate2::{Compression, write::GzEncoder};
+use serde_json::Value;
+
+#[derive(Clone, Debug)]
+pub struct Recorded {
+    pub method: String,
+    pub target: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Recorded {
+    pub fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or_default()
+    }
+
+    pub fn query(&self, key: &str) -> Option<String> {
+        let (_, query) = self.target.split_once('?')?;
+        query.split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| decode(v))
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+}
+
+fn decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap();
+                out.push(u8::from_str_radix(hex, 16).unwrap());
+                i += 2;
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8(out).unwrap()
+}
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Vec<u8>,
+}
+
+pub fn success(json: Value) -> Response {
+    raw(200, &serde_json::json!({ "success": json }).to_string())
+}
+
+pub fn error(id: i32, description: &str) -> Response {
+    raw(200, &serde_json::json!({ "error": { "id": id, "description": description } }).to_string())
+}
+
+pub fn raw(status: u16, body: &str) -> Response {
+    Response { status, headers: vec![], body: body.into() }
+}
+
+// A success envelope sent with Content-Encoding: gzip
+pub fn gzipped(json: Value) -> Response {
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    encoder.write_all(serde_json::json!({ "success": json }).to_string().as_bytes()).unwrap();
+    Response { status: 200, headers: vec![("Content-Encoding", "gzip".into())], body: encoder.finish().unwrap() }
+}
+
+// Closes the connection without answering, as a server that went away mid-request would
+pub fn hang_up() -> Response {
+    Response { status: 0, headers: vec![], body: vec![] }
+}
+
+type Handler = dyn Fn(&Recorded) -> Response + Send + Sync;
+
+pub struct MockServer {
+    url: String,
+    requests: Arc<Mutex<Vec<Recorded>>>,
+    stopped: Arc<AtomicBool>,
+    accepting: Option<JoinHandle<()>>,
+}
+
+impl MockServer {
+    pub fn start(handler: impl Fn(&Recorded) -> Response + Send + Sync + 'static) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+
+        let handler: Arc<Handler> = Arc::new(handler);
+        let recorded = requests.clone();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stopping = stopped.clone();
+        let accepting = thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if stopping.load(Ordering::SeqCst) {
+                    break;
+                }
+                let handler = handler.clone();
+                let recorded = recorded.clone();
+                thread::spawn(move || serve(stream, &*handler, &recorded));
+            }
+        });
+
+        Self { url, requests, stopped, accepting: Some(accepting) }
+    }
+
+    // Closes the listening socket, later connections are refused
+    pub fn stop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wakes the accepting thread up so that it sees the flag
+        let _ = TcpStream::connect(self.url.trim_start_matches("http://"));
+        if let Some(accepting) = self.accepting.take() {
+            accepting.join().unwrap();
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn requests(&self) -> Vec<Recorded> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    // The last request whose path ends with `suffix`
+    pub fn last(&self, suffix: &str) -> Recorded {
+        self.requests().into_iter().rev().find(|r| r.path().ends_with(suffix))
+            .unwrap_or_else(|| panic!("no request to {suffix}"))
+    }
+}
+
+fn serve(stream: TcpStream, handler: &Handler, recorded: &Mutex<Vec<Recorded>>) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+    let mut line = String::new();
+    if reader.read_line(&mut line).unwrap_or(0) == 0 {
+        return;
+    }
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let target = parts.next().unwrap_or_default().to_string();
+
+    let mut headers = vec![];
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((k, v)) = line.split_once(':') {
+            headers.push((k.trim().to_string(), v.trim().to_string()));
+        }
+    }
+
+    let length = headers.iter().find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+                        .and_then(|(_, v)| v.parse().ok()).unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).unwrap();
+
+    let request = Recorded { method, target, headers, body: String::from_utf8_lossy(&body).into() };
+    recorded.lock().unwrap().push(request.clone());
+
+    let response = handler(&request);
+    if response.status == 0 {
+        return;
+    }
+    let mut head = format!("HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nConnection: close\r\n", response.status, response.body.len());
+    for (k, v) in &response.headers {
+        head += &format!("{k}: {v}\r\n");
+    }
+    head += "\r\n";
+    let mut stream = stream;
+    let _ = stream.write_all(head.as_bytes()).and_then(|_| stream.write_all(&response.body));
+}
@@ -0,0 +1,582 @@
+use std::sync::atomic::AtomicUsize;
+use serde_json::json;
+
+use crate::mock::{self, MockServer, Recorded, Response};
+use super::*;
+
+// Answers register with player 5 (player id 50) and hands everything else to `handler`
+fn server(handler: impl Fn(&Recorded) -> Response + Send + Sync + 'static) -> MockServer {
+    MockServer::start(move |r| match r.path() {
+        "/register" => mock::success(json!({ "player": { "nickname": r.query("name"), "id": 5, "player_id": 50 } })),
+        _ => handler(r),
+    })
+}
+
+fn registered(server: &MockServer) -> Connection {
+    let mut c = Connection::new(server.url());
+    c.register("alice".into()).unwrap();
+    c.disable_auto_unregister();
+    c
+}
+
+#[test]
+fn error_with_only_an_id() {
+    let e = Error::from_json(&json!({ "id": 3 }));
+    assert_eq!((e.id, e.description, e.info), (3, None, None));
+}
+
+#[test]
+fn error_with_id_and_description() {
+    let e = Error::from_json(&json!({ "id": 4, "description": "not found" }));
+    assert_eq!((e.id, e.description.as_deref(), e.info), (4, Some("not found"), None));
+}
+
+#[test]
+fn empty_error_object() {
+    let e = Error::from_json(&json!({}));
+    assert_eq!(e.id, Error::LOCAL_ERROR_ID);
+    assert_eq!(e.info.as_deref(), Some("{}"));
+}
+
+#[test]
+fn error_info_as_json() {
+    let e = Error::new(7, None, Some(r#"{"player": 3}"#.into()));
+    assert_eq!(e.info_as_json(), Some(json!({ "player": 3 })));
+    assert_eq!(Error::new(7, None, Some("plain".into())).info_as_json(), None);
+}
+
+#[test]
+fn player_from_entry() {
+    assert_eq!(Player::from_entry("12:bob").unwrap(), Player::new("bob", 12));
+    assert_eq!(Player::from_entry("12:b:o").unwrap(), Player::new("b:o", 12));
+    assert!(Player::from_entry("bob").is_err());
+    assert!(Player::from_entry("x:bob").is_err());
+}
+
+#[test]
+fn player_list_skips_malformed_entries() {
+    let players = Player::list_from_json(&json!({ "players": ["1:a", "broken", 7, "2:b"] }), "players").unwrap();
+    assert_eq!(players, vec![Player::new("a", 1), Player::new("b", 2)]);
+}
+
+#[test]
+fn player_display() {
+    assert_eq!(Player::new("bob", 12).to_string(), "bob (12)");
+}
+
+#[test]
+fn nickname_validation() {
+    assert!(validate_nickname("alice").is_ok());
+    assert!(validate_nickname("").is_err());
+    assert!(validate_nickname("a:b").is_err());
+}
+
+#[test]
+fn register_rejects_invalid_nicknames_locally() {
+    let mut c = Connection::new("http://127.0.0.1:9");
+    assert!(matches!(c.register("a:b".into()), Err(ConnectionError::Protocol(_))));
+    assert!(matches!(c.register("".into()), Err(ConnectionError::Protocol(_))));
+}
+
+#[test]
+fn urls() {
+    for base in ["http://x", "http://x/"] {
+        assert_eq!(Connection::new(base).url("players"), "http://x/players");
+        assert_eq!(Connection::new(base).url("/5/state"), "http://x/5/state");
+    }
+    assert_eq!(Connection::new("http://host/api/").url("players"), "http://host/api/players");
+}
+
+#[test]
+fn id_scoped_url_keeps_the_path_prefix() {
+    let server = MockServer::start(|r| match r.path() {
+        "/api/register" => mock::success(json!({ "player": { "nickname": "alice", "id": 5, "player_id": 50 } })),
+        _ => mock::success(json!({ "state": 1, "players": [] })),
+    });
+    let mut c = Connection::new(&format!("{}/api/", server.url()));
+    c.register("alice".into()).unwrap();
+    c.disable_auto_unregister();
+
+    c.get_state().unwrap();
+    c.get_players().unwrap();
+    assert!(server.requests().iter().any(|r| r.path() == "/api/5/state"));
+    assert!(server.requests().iter().any(|r| r.path() == "/api/players"));
+}
+
+#[test]
+fn search_prefs_query() {
+    let pairs = |prefs: &SearchPrefs| query_pairs(&prefs.query()).into_iter()
+        .map(|(k, v)| format!("{k}={v}")).collect::<Vec<_>>();
+
+    assert!(pairs(&SearchPrefs::default()).is_empty());
+    assert_eq!(pairs(&SearchPrefs { min_rating: Some(1000), ..Default::default() }), ["min_rating=1000"]);
+    let prefs = SearchPrefs { min_rating: Some(1), max_rating: Some(2), game_mode: Some("blitz".into()) };
+    assert_eq!(pairs(&prefs), ["min_rating=1", "max_rating=2", "game_mode=blitz"]);
+}
+
+#[test]
+fn get_nickname_before_register() {
+    assert!(Connection::new("http://127.0.0.1:9").get_nickname().is_err());
+}
+
+#[test]
+fn register_stores_the_session() {
+    let server = server(|_| mock::success(json!({})));
+    let c = registered(&server);
+
+    assert_eq!(server.last("register").query("name").as_deref(), Some("alice"));
+    assert_eq!(c.get_nickname().unwrap(), "alice");
+    assert_eq!((c.id(), c.player_id()), (Some(5), Some(50)));
+    assert_eq!(c.get_stored_state(), State::Idle);
+}
+
+#[test]
+fn get_players_and_get_player() {
+    let server = server(|_| mock::success(json!({ "players": ["1:a", "bad", "2:b"] })));
+    let c = Connection::new(server.url());
+
+    assert_eq!(c.get_players().unwrap(), vec![Player::new("a", 1), Player::new("b", 2)]);
+    assert_eq!(c.get_player(2).unwrap(), Some(Player::new("b", 2)));
+    assert_eq!(c.get_player(3).unwrap(), None);
+    assert_eq!(c.resolve_nickname(1).as_deref(), Some("a"));
+}
+
+#[test]
+fn get_requests() {
+    let server = server(|_| mock::success(json!({ "requests": ["3:carol"] })));
+    let mut c = registered(&server);
+
+    assert_eq!(c.get_requests().unwrap(), vec![Player::new("carol", 3)]);
+    assert_eq!(server.last("requests").path(), "/5/requests");
+}
+
+#[test]
+fn send_and_get_messages() {
+    let server = server(|r| match r.path() {
+        "/5/state" => mock::success(json!({ "state": 3 })),
+        _ if r.method == "POST" => mock::success(json!({ "message_id": 9 })),
+        _ => mock::success(json!({ "messages": ["hi", { "sender_id": 3, "text": "hello" }] })),
+    });
+    let mut c = registered(&server);
+    c.get_state().unwrap();
+
+    assert_eq!(c.send_message("hi \"there\"".into()).unwrap(), Some(9));
+    let sent = server.last("messages");
+    assert_eq!(sent.header("content-type"), Some("application/json"));
+    assert_eq!(serde_json::from_str::<Value>(&sent.body).unwrap(), json!({ "text": "hi \"there\"" }));
+
+    assert_eq!(c.get_messages().unwrap(), ["hi", "hello"]);
+}
+
+#[test]
+fn missing_messages_list_is_an_error() {
+    let server = server(|_| mock::success(json!({})));
+    let mut c = registered(&server);
+    assert!(matches!(c.get_messages(), Err(ConnectionError::Protocol(_))));
+}
+
+#[test]
+fn ordered_messages_only_dedup_by_index() {
+    let server = server(|_| mock::success(json!({ "messages": [
+        "hi", "hi",
+        { "text": "a", "index": 1 }, { "text": "a", "index": 1 }, { "text": "b", "index": 2 },
+    ] })));
+    let mut c = registered(&server);
+
+    assert_eq!(c.get_messages_ordered(false, true).unwrap(), ["hi", "hi", "a", "b"]);
+    assert_eq!(c.get_messages_ordered(true, false).unwrap(), ["b", "a", "a", "hi", "hi"]);
+}
+
+#[test]
+fn error_envelope() {
+    let server = server(|_| mock::error(42, "already in game"));
+    let mut c = registered(&server);
+
+    let e = c.search().unwrap_err();
+    assert!(matches!(&e, ConnectionError::Server(e) if e.description.as_deref() == Some("already in game")));
+    assert_eq!(e.server_error_id(), Some(42));
+    assert_eq!(ConnectionError::Protocol("x".into()).server_error_id(), None);
+}
+
+#[test]
+fn join_private_game_keeps_other_server_errors() {
+    let server = server(|r| match r.query("code").as_deref() {
+        Some("old") => mock::error(Error::INVALID_JOIN_CODE_ID, "expired"),
+        _ => mock::error(42, "already in game"),
+    });
+    let mut c = registered(&server);
+
+    assert!(matches!(c.join_private_game("old"), Err(ConnectionError::Protocol(_))));
+    assert_eq!(c.join_private_game("new").unwrap_err().server_error_id(), Some(42));
+}
+
+#[test]
+fn conversions() {
+    let unreachable = Connection::new("http://127.0.0.1:9").get_players();
+    assert!(matches!(unreachable, Err(ConnectionError::Http(_))));
+
+    let server = server(|r| match r.path() {
+        "/players" => mock::raw(200, "not json"),
+        "/config" => mock::raw(503, r#"{"message":"Service Unavailable"}"#),
+        "/version" => mock::raw(500, r#"{"success":{"version":"1"}}"#),
+        "/error_description" => mock::raw(409, r#"{"error":{"id":8}}"#),
+        _ => mock::raw(503, "<html>down</html>"),
+    });
+    let c = Connection::new(server.url());
+    assert!(matches!(c.get_players(), Err(ConnectionError::Json(_))));
+    assert!(matches!(c.get_server_time(), Err(ConnectionError::HttpStatus(status, body)) if status.as_u16() == 503 && body.contains("down")));
+    assert!(matches!(c.get_server_config(), Err(ConnectionError::HttpStatus(status, _)) if status.as_u16() == 503));
+    assert!(matches!(c.server_version(), Err(ConnectionError::HttpStatus(status, _)) if status.as_u16() == 500));
+    assert_eq!(c.get_error_description(1).unwrap_err().server_error_id(), Some(8));
+
+    let e: ConnectionError = Error::new(1, None, None).into();
+    assert!(matches!(e, ConnectionError::Server(_)));
+}
+
+#[test]
+fn dry_run_answers_locally() {
+    let mut c = Connection::new("http://127.0.0.1:9");
+    c.dry_run(true);
+
+    c.register("alice".into()).unwrap();
+    assert_eq!(c.get_error_description(1).unwrap(), "");
+    assert!(c.get_leaderboard().unwrap().is_empty());
+    assert!(c.get_active_games().unwrap().is_empty());
+    assert_eq!(c.create_private_game().unwrap(), "dry-run");
+    assert_eq!(c.get_state().unwrap(), State::Idle);
+}
+
+#[test]
+fn wait_for_messages_doesnt_spin_on_early_empty_answers() {
+    let server = server(|_| mock::success(json!({ "messages": [] })));
+    let mut c = registered(&server);
+
+    assert!(c.wait_for_messages(Duration::from_millis(1500)).unwrap().is_empty());
+    let polls = server.requests().iter().filter(|r| r.path() == "/5/messages").count();
+    assert!(polls <= 3, "{polls} polls");
+    assert!(server.requests().iter().all(|r| r.query("wait").as_deref() != Some("2")));
+}
+
+#[test]
+fn wait_for_move_doesnt_spin_on_early_empty_answers() {
+    let server = server(|r| match r.path() {
+        "/5/state" => mock::success(json!({ "state": 3 })),
+        _ => mock::success(json!({ "state": 3, "moves": [] })),
+    });
+    let mut c = registered(&server);
+    c.get_state().unwrap();
+
+    assert_eq!(c.wait_for_move(Duration::from_millis(1500)).unwrap(), None);
+    let polls = server.requests().iter().filter(|r| r.path() == "/5/move").count();
+    assert!(polls <= 3, "{polls} polls");
+}
+
+#[test]
+fn unreadable_answers_are_protocol_errors() {
+    let server = server(|r| match r.path() {
+        "/players" => mock::success(json!({})),
+        "/5/requests" => mock::success(json!({ "requests": "3:carol" })),
+        _ => mock::raw(200, r#"{"neither": 1}"#),
+    });
+    let mut c = registered(&server);
+
+    for e in [c.get_players().unwrap_err(), c.get_requests().unwrap_err(), c.get_server_time().unwrap_err()] {
+        assert!(matches!(e, ConnectionError::Protocol(_)), "{e}");
+        assert_eq!(e.server_error_id(), None);
+    }
+    let e: ConnectionError = Error::from_json(&json!({ "description": "no id" })).into();
+    assert!(matches!(e, ConnectionError::Protocol(_)));
+}
+
+// Answers with `failure` for the first `failures` requests and with `then` afterwards
+fn failing(failures: usize, failure: fn() -> Response, then: Value) -> impl Fn(&Recorded) -> Response + Send + Sync {
+    let seen = AtomicUsize::new(0);
+    move |_| if seen.fetch_add(1, Ordering::SeqCst) < failures { failure() } else { mock::success(then.clone()) }
+}
+
+#[test]
+fn lost_connections_are_retried() {
+    let server = MockServer::start(failing(2, mock::hang_up, json!({ "players": ["1:a"] })));
+    let c = Connection::new(server.url()).with_retries(3, Duration::from_millis(10));
+
+    assert_eq!(c.get_players().unwrap(), vec![Player::new("a", 1)]);
+    assert_eq!(server.requests().len(), 3);
+}
+
+#[test]
+fn retries_run_out() {
+    let server = MockServer::start(failing(2, mock::hang_up, json!({ "players": [] })));
+    let c = Connection::new(server.url()).with_retries(1, Duration::from_millis(10));
+
+    assert!(matches!(c.get_players(), Err(ConnectionError::Http(_))));
+    assert_eq!(server.requests().len(), 2);
+}
+
+#[test]
+fn retryable_server_errors_are_sent_again() {
+    let server = MockServer::start(failing(2, || mock::error(7, "busy"), json!({ "players": [] })));
+    let mut c = Connection::new(server.url()).with_retries(3, Duration::from_millis(10));
+    c.retry_on_error_ids(&[7]);
+
+    assert!(c.get_players().unwrap().is_empty());
+    assert_eq!(server.requests().len(), 3);
+}
+
+#[test]
+fn other_server_errors_fail_at_once() {
+    let server = MockServer::start(failing(2, || mock::error(8, "nope"), json!({ "players": [] })));
+    let mut c = Connection::new(server.url()).with_retries(3, Duration::from_millis(10));
+    c.retry_on_error_ids(&[7]);
+
+    assert_eq!(c.get_players().unwrap_err().server_error_id(), Some(8));
+    assert_eq!(server.requests().len(), 1);
+}
+
+#[test]
+fn auth_token_is_sent_until_cleared() {
+    let server = server(|_| mock::success(json!({ "players": [] })));
+    let mut c = Connection::new(server.url());
+
+    c.set_auth_token(Some("secret"));
+    c.get_players().unwrap();
+    assert_eq!(server.last("players").header("authorization"), Some("Bearer secret"));
+
+    c.set_auth_token(None);
+    c.get_players().unwrap();
+    assert_eq!(server.last("players").header("authorization"), None);
+}
+
+#[test]
+fn gzip_answers_are_decoded() {
+    let server = MockServer::start(|_| mock::gzipped(json!({ "players": ["1:a"] })));
+    let c = Connection::new(server.url());
+
+    assert_eq!(c.get_players().unwrap(), vec![Player::new("a", 1)]);
+    assert!(server.last("players").header("accept-encoding").is_some_and(|v| v.contains("gzip")));
+}
+
+#[test]
+fn compression_can_be_disabled() {
+    let server = server(|_| mock::success(json!({ "players": [] })));
+    let c = Connection::new(server.url()).with_compression(false);
+
+    c.get_players().unwrap();
+    assert_eq!(server.last("players").header("accept-encoding"), Some("identity"));
+}
+
+#[test]
+fn wrong_state_calls_fail_locally() {
+    let server = server(|_| mock::success(json!({ "state": 3 })));
+
+    let mut c = Connection::new(server.url());
+    assert!(matches!(c.search(), Err(ConnectionError::Protocol(_))));
+    assert!(matches!(c.send_request(3), Err(ConnectionError::Protocol(_))));
+    assert!(matches!(c.send_message("hi".into()), Err(ConnectionError::Protocol(_))));
+    assert!(server.requests().is_empty());
+
+    let mut c = registered(&server);
+    assert!(matches!(c.send_message("hi".into()), Err(ConnectionError::Protocol(_))));
+    c.get_state().unwrap();
+    assert!(matches!(c.search(), Err(ConnectionError::Protocol(_))));
+    assert_eq!(server.requests().len(), 2);
+}
+
+#[test]
+fn shared_connection_across_threads() {
+    let server = server(|r| match r.path() {
+        "/5/state" => mock::success(json!({ "state": 1 })),
+        _ => mock::success(json!({ "players": ["5:alice"] })),
+    });
+    let shared = SharedConnection::new(registered(&server));
+
+    let other = shared.clone();
+    let polling = std::thread::spawn(move || (0..5).map(|_| other.get_state().unwrap()).collect::<Vec<_>>());
+    for _ in 0..5 {
+        assert_eq!(shared.get_players().unwrap(), vec![Player::new("alice", 5)]);
+    }
+
+    assert!(polling.join().unwrap().iter().all(|state| *state == State::Idle));
+    assert_eq!(shared.get_stored_state(), State::Idle);
+    assert_eq!(server.requests().iter().filter(|r| r.path() == "/5/state").count(), 5);
+}
+
+#[test]
+fn reconnect_into_a_game() {
+    let server = server(|r| match r.path() {
+        "/5/state" => mock::success(json!({ "state": 3, "opponent": "3:carol", "game_id": 77 })),
+        _ => mock::success(json!({ "state": 3, "moves": ["e4", "e5"] })),
+    });
+    let mut c = Connection::new(server.url());
+    c.disable_auto_unregister();
+
+    assert_eq!(c.reconnect(5, 50, "alice").unwrap(), State::Playing);
+    assert_eq!(c.opponent(), Some(&Player::new("carol", 3)));
+    assert_eq!(c.current_game(), Some(77));
+    assert_eq!(c.get_moves().unwrap(), ["e4", "e5"]);
+    assert_eq!(server.requests().iter().filter(|r| r.path() == "/5/state").count(), 1);
+
+    // Only moves after the synced ones are reported
+    assert_eq!(c.wait_for_move(Duration::from_millis(100)).unwrap(), None);
+}
+
+#[test]
+fn register_or_resume_fresh_and_resumed() {
+    let server = MockServer::start(|r| match r.path() {
+        "/register" if r.query("name").as_deref() == Some("bob") =>
+            mock::success(json!({ "player": { "nickname": "bob", "id": 6, "player_id": 60 }, "resumed": true })),
+        "/register" => mock::success(json!({ "player": { "nickname": "alice", "id": 5, "player_id": 50 } })),
+        _ => mock::success(json!({ "state": 2 })),
+    });
+
+    let mut fresh = Connection::new(server.url());
+    fresh.disable_auto_unregister();
+    assert_eq!(fresh.register_or_resume("alice").unwrap(), State::Idle);
+    assert_eq!(server.last("register").query("resume").as_deref(), Some("true"));
+    assert_eq!(fresh.id(), Some(5));
+
+    let mut resumed = Connection::new(server.url());
+    resumed.disable_auto_unregister();
+    assert_eq!(resumed.register_or_resume("bob").unwrap(), State::Searching);
+    assert_eq!(server.last("state").path(), "/6/state");
+}
+
+#[test]
+fn register_or_resume_when_registered() {
+    let server = server(|_| mock::success(json!({ "state": 2 })));
+    let mut c = registered(&server);
+
+    assert_eq!(c.register_or_resume("alice").unwrap(), State::Searching);
+    assert!(matches!(c.register_or_resume("bob"), Err(ConnectionError::Protocol(_))));
+    assert_eq!(c.get_nickname().unwrap(), "alice");
+    assert_eq!(server.requests().iter().filter(|r| r.path() == "/register").count(), 1);
+}
+
+#[test]
+fn player_and_error_serde_round_trip() {
+    let player = Player::new("bob", 12);
+    assert_eq!(serde_json::from_str::<Player>(&serde_json::to_string(&player).unwrap()).unwrap(), player);
+
+    let error = Error::new(4, Some("not found".into()), Some("x".into()));
+    let back = Error::from_json(&serde_json::to_value(&error).unwrap());
+    assert_eq!((back.id, back.description, back.info), (error.id, error.description, error.info));
+}
+
+#[test]
+fn get_state_reports_a_stopped_server_as_disconnected() {
+    let mut server = server(|_| mock::success(json!({ "state": 1 })));
+    let mut c = registered(&server);
+    assert_eq!(c.get_state().unwrap(), State::Idle);
+
+    server.stop();
+    assert_eq!(c.get_state().unwrap(), State::Disconnected(DisconnectReason::NetworkError));
+    assert_eq!(c.get_stored_state(), State::Disconnected(DisconnectReason::NetworkError));
+}
+
+#[test]
+fn ping() {
+    assert!(matches!(Connection::new("http://127.0.0.1:9").ping(), Err(ConnectionError::Http(_))));
+
+    let server = MockServer::start(|_| mock::error(0, "unknown error id"));
+    assert!(Connection::new(server.url()).ping().is_ok());
+    let server = MockServer::start(|_| mock::raw(200, "[]"));
+    assert!(matches!(Connection::new(server.url()).ping(), Err(ConnectionError::Protocol(_))));
+}
+
+#[test]
+fn rate_limit_spaces_requests_out() {
+    let server = server(|_| mock::success(json!({ "players": [] })));
+    let c = Connection::new(server.url()).with_rate_limit(10);
+
+    // The first 10 go out at once, the other 5 at 10 per second
+    let started = Instant::now();
+    for _ in 0..15 {
+        c.get_players().unwrap();
+    }
+    assert!(started.elapsed() >= Duration::from_millis(450), "{:?}", started.elapsed());
+}
+
+#[test]
+fn reconnect_loop_waits_for_the_server() {
+    let server = server(failing(2, mock::hang_up, json!({ "state": 1 })));
+    let mut c = registered(&server);
+
+    assert_eq!(c.reconnect_loop(5, Duration::from_millis(10)).unwrap(), State::Idle);
+    assert_eq!(server.requests().iter().filter(|r| r.path() == "/5/state").count(), 3);
+    assert_eq!(server.requests().iter().filter(|r| r.path() == "/register").count(), 1);
+}
+
+#[test]
+fn registration_accessors() {
+    let server = server(|_| mock::success(json!({ "state": 3 })));
+    let fresh = Connection::new(server.url());
+    assert!(!fresh.is_registered() && !fresh.is_in_game());
+
+    let mut c = registered(&server);
+    assert!(c.is_registered() && !c.is_in_game());
+    c.get_state().unwrap();
+    assert!(c.is_in_game());
+}
+
+#[test]
+fn request_observer_sees_each_round_trip() {
+    let server = server(|_| mock::success(json!({ "players": [] })));
+    let mut c = Connection::new(server.url());
+    let seen = Arc::new(Mutex::new(vec![]));
+    let observed = seen.clone();
+    c.set_request_observer(Box::new(move |command, elapsed| observed.lock().unwrap().push((command.to_string(), elapsed))));
+
+    c.get_players().unwrap();
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen.len(), 1);
+    assert_eq!(seen[0].0, "players");
+    assert!(seen[0].1 > Duration::ZERO && seen[0].1 < Duration::from_secs(5));
+}
+
+#[test]
+fn change_nickname_reports_a_taken_nickname() {
+    let server = server(|r| match r.query("name").as_deref() {
+        Some("bob") => mock::error(Error::NICKNAME_TAKEN_ID, "nickname taken"),
+        _ => mock::success(json!({})),
+    });
+    let mut c = registered(&server);
+
+    assert_eq!(c.change_nickname("bob").unwrap_err().server_error_id(), Some(Error::NICKNAME_TAKEN_ID));
+    assert_eq!(c.get_nickname().unwrap(), "alice");
+    c.change_nickname("carol").unwrap();
+    assert_eq!(c.get_nickname().unwrap(), "carol");
+    assert!(server.requests().iter().all(|r| r.path() != "/players"));
+}
+
+#[test]
+fn players_page() {
+    let paging = server(|r| match r.query("offset").as_deref() {
+        Some("0") => mock::success(json!({ "players": ["1:a", "2:b"] })),
+        _ => mock::success(json!({ "players": ["3:c"] })),
+    });
+    let c = Connection::new(paging.url());
+    assert_eq!(c.get_players_page(0, 2).unwrap(), (vec![Player::new("a", 1), Player::new("b", 2)], true));
+    assert_eq!(c.get_players_page(2, 2).unwrap(), (vec![Player::new("c", 3)], false));
+
+    let full_list = server(|_| mock::success(json!({ "players": ["1:a", "2:b", "3:c"] })));
+    let c = Connection::new(full_list.url());
+    assert_eq!(c.get_players_page(1, 1).unwrap(), (vec![Player::new("b", 2)], true));
+    assert_eq!(c.get_players_page(2, 2).unwrap(), (vec![Player::new("c", 3)], false));
+}
+
+#[test]
+fn send_messages_checks_every_line_first() {
+    let server = server(|r| match r.path() {
+        "/5/state" => mock::success(json!({ "state": 3 })),
+        "/config" => mock::success(json!({ "max_message_length": 5 })),
+        _ => mock::success(json!({})),
+    });
+    let mut c = registered(&server);
+    c.get_state().unwrap();
+    c.get_server_config().unwrap();
+
+    let lines = ["hi".to_string(), "much too long".to_string()];
+    assert!(matches!(c.send_messages(&lines), Err(ConnectionError::Protocol(_))));
+    assert!(server.requests().iter().all(|r| r.path() != "/5/messages"));
+    c.send_messages(&lines[..1]).unwrap();
+    assert_eq!(server.last("messages").query("batch").as_deref(), Some("true"));
+}
@@ -0,0 +1,101 @@
+use std::{pin::Pin, task::{Context, Poll}};
+use futures_util::Stream;
+use log::{debug, trace};
+use serde_json::Value;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, tungstenite::{self, Message, client::IntoClientRequest, http::HeaderValue}};
+
+use crate::{Connection, ConnectionError, Event, Player, State};
+
+impl From<tungstenite::Error> for ConnectionError {
+    fn from(e: tungstenite::Error) -> Self {
+        ConnectionError::Protocol(format!("websocket: {e}"))
+    }
+}
+
+/// Events pushed by the server over a WebSocket. The socket is driven by tokio, so the stream has to be
+/// polled from inside a tokio runtime; the blocking Connection is still used for sending.
+pub struct EventStream {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl EventStream {
+    // Frames look like {"type": "message", "text": "..."}, unknown types are skipped
+    fn parse(text: &str) -> Result<Option<Event>, ConnectionError> {
+        let json: Value = serde_json::from_str(text)?;
+        let kind = json["type"].as_str().ok_or_else(|| ConnectionError::Protocol("event without a type".into()))?;
+
+        let event = match kind {
+            "message" => Event::NewMessage(json["text"].as_str().unwrap_or("").to_string()),
+            "request" => {
+                let entry = json["player"].as_str().ok_or_else(|| ConnectionError::Protocol("request event without a player".into()))?;
+                Event::NewRequest(Player::from_entry(entry)?)
+            }
+            "request_withdrawn" => {
+                let id = json["player_id"].as_u64().ok_or_else(|| ConnectionError::Protocol("withdrawn event without a player_id".into()))?;
+                Event::RequestWithdrawn(id)
+            }
+            "state" => Event::StateChanged(State::from_json(&json)?),
+            "game_started" => Event::GameStarted,
+            "game_ended" => Event::GameEnded,
+            _ => {
+                debug!("Skipping unknown event type {kind}");
+                return Ok(None);
+            }
+        };
+        Ok(Some(event))
+    }
+}
+
+impl Stream for EventStream {
+    type Item = Result<Event, ConnectionError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let message = match Pin::new(&mut self.socket).poll_next(cx) {
+                Poll::Ready(Some(Ok(message))) => message,
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e.into()))),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+
+            match message {
+                Message::Text(text) => {
+                    trace!("Event: {text}");
+                    match EventStream::parse(&text) {
+                        Ok(Some(event)) => return Poll::Ready(Some(Ok(event))),
+                        Ok(None) => continue,
+                        Err(e) => return Poll::Ready(Some(Err(e))),
+                    }
+                }
+                Message::Close(_) => return Poll::Ready(None),
+                // Pings are answered by tungstenite itself
+                _ => continue,
+            }
+        }
+    }
+}
+
+impl Connection {
+    /// Opens {id}/ws on the same server, with the http(s) scheme swapped for ws(s).
+    /// The handshake carries the bearer token from set_auth_token like every other request.
+    pub async fn connect_ws(&self) -> Result<EventStream, ConnectionError> {
+        let id = self.require_registered()?.id;
+        let url = self.url(&format!("{id}/ws"));
+        let url = match url.strip_prefix("http") {
+            Some(rest) => format!("ws{rest}"),
+            None => url,
+        };
+        debug!("Connecting to {url}");
+
+        let mut request = url.into_client_request()?;
+        if let Some(token) = &self.auth_token {
+            let value = HeaderValue::from_str(&format!("Bearer {token}"))
+                .map_err(|_| ConnectionError::Protocol("auth token is not a valid header value".into()))?;
+            request.headers_mut().insert(tungstenite::http::header::AUTHORIZATION, value);
+        }
+
+        let (socket, _) = tokio_tungstenite::connect_async(request).await?;
+        Ok(EventStream { socket })
+    }
+}